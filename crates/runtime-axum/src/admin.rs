@@ -0,0 +1,254 @@
+use axum::{extract, middleware, routing, Router};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use runtime::log::{LogFilter, LogLine};
+
+
+/// Bearer token guarding admin routes
+#[derive(Clone)]
+pub struct AdminAuth(Arc<str>);
+impl AdminAuth {
+    pub fn bearer(token: impl Into<String>) -> Self {
+        AdminAuth(token.into().into())
+    }
+
+    fn check(&self, headers: &axum::http::HeaderMap) -> bool {
+        let Some(given) = headers.get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // Don't leak how much of the token matched through timing
+        let (a, b) = (given.as_bytes(), self.0.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    /// Rejects requests without `Authorization: Bearer <token>` with 401
+    pub fn protect<S>(&self, router: Router<S>) -> Router<S>
+        where S: Clone + Send + Sync + 'static
+    {
+        let auth = self.clone();
+        router.route_layer(middleware::from_fn(move |req: extract::Request, next: middleware::Next| {
+            let authorized = auth.check(req.headers());
+            async move {
+                if authorized {
+                    next.run(req).await
+                } else {
+                    warn!("Rejected unauthorized admin request to {}", req.uri());
+                    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response()
+                }
+            }
+        }))
+    }
+}
+
+
+#[derive(serde::Deserialize)]
+struct SetFilterQuery {
+    /// Seconds until the startup (or last reloaded) filter comes back
+    revert_after: Option<u64>,
+}
+
+/// `/log-filter`: GET shows the filter in effect, PUT replaces it with the `RUST_LOG`-style body
+/// (only for `?revert_after=<secs>` seconds if given), DELETE goes back to the startup filter or
+/// the one from the last reload. Reloads don't replace a filter set through PUT.
+pub fn log_filter_router<S>(filter: LogFilter, auth: &AdminAuth) -> Router<S>
+    where S: Clone + Send + Sync + 'static
+{
+    let router = Router::new()
+        .route("/log-filter", routing::get(|extract::State(filter): extract::State<LogFilter>| async move {
+            filter.current() + "\n"
+        })
+        .put(|
+            extract::State(filter): extract::State<LogFilter>,
+            extract::Query(query): extract::Query<SetFilterQuery>,
+            spec: String,
+        | async move {
+            let spec = spec.trim();
+            let result = match query.revert_after {
+                Some(secs) => filter.set_for(spec, Duration::from_secs(secs)),
+                None => filter.set(spec),
+            };
+            filter_response(&filter, result)
+        })
+        .delete(|extract::State(filter): extract::State<LogFilter>| async move {
+            let result = filter.reset();
+            filter_response(&filter, result)
+        }))
+        .with_state(filter);
+    auth.protect(router)
+}
+
+fn filter_response(filter: &LogFilter, result: Result<(), runtime::log::LogFilterError>) -> Response {
+    match result {
+        Ok(()) => (filter.current() + "\n").into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{}\n", runtime::utils::format_error_disp(&e))).into_response(),
+    }
+}
+
+
+#[derive(serde::Deserialize)]
+struct LogStreamQuery {
+    /// Least severe level to send, `trace` by default
+    level: Option<String>,
+    /// Comma separated target prefixes like `tower_http,my_app::db`; everything by default
+    target: Option<String>,
+    /// Whether to start with the lines logged before connecting
+    history: Option<bool>,
+}
+
+struct LogStreamFilter {
+    level: tracing::Level,
+    targets: Vec<String>,
+}
+impl LogStreamFilter {
+    fn new(query: &LogStreamQuery) -> Result<Self, String> {
+        let level = match &query.level {
+            Some(level) => level.parse().map_err(|_| format!("Invalid level {:?}", level))?,
+            None => tracing::Level::TRACE,
+        };
+        let targets = query.target.iter()
+            .flat_map(|t| t.split(','))
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .collect();
+        Ok(LogStreamFilter { level, targets })
+    }
+
+    fn matches(&self, line: &LogLine) -> bool {
+        // Level ordering is by verbosity, TRACE > ERROR
+        line.level <= self.level && (self.targets.is_empty() || self.targets.iter().any(|t| {
+            line.target.strip_prefix(&t[..]).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        }))
+    }
+}
+
+#[derive(serde::Serialize)]
+struct LogMessage<'a> {
+    level: &'a str,
+    target: &'a str,
+    html: &'a str,
+}
+impl<'a> LogMessage<'a> {
+    fn new(line: &'a LogLine) -> Self {
+        LogMessage { level: line.level.as_str(), target: &line.target, html: &line.html }
+    }
+    fn skipped(count: u64) -> String {
+        let html = format!("... {count} line(s) skipped, the connection fell behind");
+        serde_json::to_string(&LogMessage { level: "WARN", target: "", html: &html }).unwrap_or_default()
+    }
+}
+
+/// A log line that passed the filter, or the number of lines lost to lagging
+enum StreamItem {
+    Line(Arc<LogLine>),
+    Skipped(u64),
+}
+impl StreamItem {
+    fn to_json(&self) -> String {
+        match self {
+            StreamItem::Line(line) => serde_json::to_string(&LogMessage::new(line)).unwrap_or_default(),
+            StreamItem::Skipped(count) => LogMessage::skipped(*count),
+        }
+    }
+}
+
+struct LogStream {
+    history: std::vec::IntoIter<Arc<LogLine>>,
+    rx: runtime::log::LogReceiver,
+    filter: LogStreamFilter,
+}
+impl LogStream {
+    fn new(query: &LogStreamQuery) -> Result<Self, (StatusCode, String)> {
+        let filter = LogStreamFilter::new(query)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        let (history, rx) = runtime::log::subscribe()
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "Logger isn't set up".to_owned()))?;
+        let history = if query.history.unwrap_or(true) { history } else { Vec::new() };
+        Ok(LogStream { history: history.into_iter(), rx, filter })
+    }
+
+    /// `None` once the logger is gone
+    async fn next(&mut self) -> Option<StreamItem> {
+        for line in self.history.by_ref() {
+            if self.filter.matches(&line) {
+                return Some(StreamItem::Line(line));
+            }
+        }
+        loop {
+            match self.rx.recv().await {
+                Ok(line) if self.filter.matches(&line) => return Some(StreamItem::Line(line)),
+                Ok(_) => {},
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(count)) => return Some(StreamItem::Skipped(count)),
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// `/logs/ws` (WebSocket) and `/logs/sse` (Server-Sent Events) streaming JSON log lines
+/// `{level, target, html}`, starting with the logger's recent history. Query parameters:
+/// `level=debug` for the least severe level, `target=tower_http,my_app` for target prefixes,
+/// `history=false` to skip the backlog. Only sees what the global log filter lets through.
+pub fn log_stream_router<S>(auth: &AdminAuth) -> Router<S>
+    where S: Clone + Send + Sync + 'static
+{
+    let router = Router::new()
+        .route("/logs/ws", routing::get(|
+            ws: extract::WebSocketUpgrade,
+            going_away: crate::server::GoingAway,
+            extract::Query(query): extract::Query<LogStreamQuery>,
+        | async move {
+            let mut stream = match LogStream::new(&query) {
+                Ok(stream) => stream,
+                Err(rejection) => return rejection.into_response(),
+            };
+            ws.on_upgrade(move |mut socket| async move {
+                use extract::ws::Message;
+                loop {
+                    tokio::select! {
+                        item = stream.next() => match item {
+                            Some(item) => if socket.send(Message::Text(item.to_json())).await.is_err() {
+                                break;
+                            },
+                            None => break,
+                        },
+                        // Only needed to notice the client going away
+                        msg = socket.recv() => if !matches!(msg, Some(Ok(_))) {
+                            break;
+                        },
+                        _ = going_away.notified() => {
+                            socket.send(crate::server::GoingAway::close_message()).await.ok();
+                            break;
+                        },
+                    }
+                }
+            })
+        }))
+        .route("/logs/sse", routing::get(|
+            going_away: crate::server::GoingAway,
+            extract::Query(query): extract::Query<LogStreamQuery>,
+        | async move {
+            use axum::response::sse::{Event, KeepAlive, Sse};
+            let stream = match LogStream::new(&query) {
+                Ok(stream) => stream,
+                Err(rejection) => return rejection.into_response(),
+            };
+            let events = futures_util::stream::unfold((stream, going_away), |(mut stream, going_away)| async move {
+                let item = tokio::select! {
+                    item = stream.next() => item?,
+                    // Let the server finish draining instead of holding the connection open
+                    _ = going_away.notified() => return None,
+                };
+                let event = Event::default().data(item.to_json());
+                Some((Ok::<_, std::convert::Infallible>(event), (stream, going_away)))
+            });
+            Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+        }));
+    auth.protect(router)
+}
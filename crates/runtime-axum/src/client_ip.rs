@@ -0,0 +1,352 @@
+use axum::{extract, middleware, routing};
+use axum::http::{HeaderMap, StatusCode};
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::server::Peer;
+
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid CIDR {:?}", .0)]
+pub struct CidrError(String);
+
+/// An IP network like `10.0.0.0/8` or `fd00::/8`; a bare address is a single-host network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_match(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_match(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+impl std::str::FromStr for Cidr {
+    type Err = CidrError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CidrError(s.to_owned());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.parse().map_err(|_| err())?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max).ok_or_else(err)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+fn prefix_match(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift == bits || (net >> shift) == (ip >> shift)
+}
+
+// Dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+
+/// The header our proxies put the client address in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    /// RFC 7239 `Forwarded: for=...`
+    Forwarded,
+    /// `X-Forwarded-For`, appended to by each proxy (nginx, HAProxy, most load balancers)
+    #[default]
+    XForwardedFor,
+    /// `X-Real-IP`, set by a single proxy
+    XRealIp,
+}
+
+/// Proxies whose forwarding header (only the one picked with [`TrustedProxies::header`], since
+/// proxies pass the others through from the client unchanged) is believed.
+/// Peers on a Unix domain socket are always trusted, since only local processes can connect.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Arc<Vec<Cidr>>,
+    header: ForwardedHeader,
+}
+impl TrustedProxies {
+    pub fn new<I>(cidrs: I) -> Result<Self, CidrError>
+        where I: IntoIterator, I::Item: AsRef<str>
+    {
+        let cidrs = cidrs.into_iter()
+            .map(|c| c.as_ref().trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { cidrs: Arc::new(cidrs), header: ForwardedHeader::default() })
+    }
+
+    /// Read the client address from this header (`X-Forwarded-For` by default), ignoring the others
+    pub fn header(self, header: ForwardedHeader) -> Self {
+        TrustedProxies { header, ..self }
+    }
+
+    /// Loopback and private network ranges
+    pub fn private() -> Self {
+        Self::new(["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1", "fc00::/7"]).unwrap()
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// The client address for a request that came from `peer` with `headers`.
+    /// `None` only for a Unix socket peer that didn't pass any forwarding headers.
+    pub fn resolve(&self, peer: &Peer, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer_ip = match peer {
+            Peer::Tcp(addr) => canonical(addr.ip()),
+            Peer::Unix(_) => return self.client_from_headers(None, headers),
+        };
+        if !self.is_trusted(peer_ip) {
+            return Some(peer_ip);
+        }
+        self.client_from_headers(Some(peer_ip), headers)
+    }
+
+    fn client_from_headers(&self, peer_ip: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        // Each proxy appends the address it got the request from, so walk back from the
+        // nearest hop until reaching one we don't trust
+        let chain = match self.header {
+            ForwardedHeader::Forwarded => forwarded_for(headers),
+            ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+            ForwardedHeader::XRealIp => x_real_ip(headers),
+        };
+        let chain = chain.unwrap_or_default();
+        let mut client = peer_ip;
+        for hop in chain.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                },
+                // Obfuscated or unknown, nothing further back can be trusted
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+type Chain = Vec<Option<IpAddr>>;
+
+// RFC 7239: Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"
+fn forwarded_for(headers: &HeaderMap) -> Option<Chain> {
+    let chain: Chain = headers.get_all("forwarded").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, value)| parse_node(value.trim().trim_matches('"')))
+        })
+        .collect();
+    (!chain.is_empty()).then_some(chain)
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Option<Chain> {
+    let chain: Chain = headers.get_all("x-forwarded-for").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect();
+    (!chain.is_empty()).then_some(chain)
+}
+
+fn x_real_ip(headers: &HeaderMap) -> Option<Chain> {
+    let value = headers.get("x-real-ip")?.to_str().ok()?;
+    Some(vec![parse_node(value.trim())])
+}
+
+// Addresses may come with a port, and IPv6 ones in brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| n.parse().ok())
+        .map(canonical)
+}
+
+
+/// Resolves the client address once per request and stores it for [`ClientIp`] and
+/// [`crate::layers::make_trace_layer`]; add it after (i.e. outside of) the trace layer
+pub fn client_ip_layer(trusted: TrustedProxies)
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    middleware::from_fn(move |mut req: extract::Request, next: middleware::Next| {
+        if let Some(peer) = peer(req.extensions()) {
+            if let Some(ip) = trusted.resolve(&peer, req.headers()) {
+                req.extensions_mut().insert(ClientIp(ip));
+            }
+        }
+        next.run(req)
+    })
+}
+
+pub(crate) fn peer(extensions: &axum::http::Extensions) -> Option<Peer> {
+    // Peer is set by our server for unix sockets too, plain axum::serve only gives SocketAddr
+    extensions.get::<extract::ConnectInfo<Peer>>()
+        .map(|c| c.0.clone())
+        .or_else(|| extensions.get::<extract::ConnectInfo<SocketAddr>>().map(|c| Peer::Tcp(c.0)))
+}
+
+/// The real client address resolved by [`client_ip_layer`], or the TCP peer address without it
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[axum::async_trait]
+impl<S> extract::FromRequestParts<S> for ClientIp where S: Send + Sync {
+    type Rejection = (StatusCode, &'static str);
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(ip) = parts.extensions.get::<ClientIp>() {
+            return Ok(*ip);
+        }
+        match peer(&parts.extensions) {
+            Some(Peer::Tcp(addr)) => Ok(ClientIp(canonical(addr.ip()))),
+            _ => Err((StatusCode::INTERNAL_SERVER_ERROR, "Client address unknown")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::UnixPeer;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn tcp(s: &str) -> Peer {
+        Peer::Tcp(SocketAddr::new(ip(s), 12345))
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.0")));
+        assert!(!net.contains(ip("::1")));
+
+        assert!(cidr("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+
+        let net = cidr("fd00::/8");
+        assert!(net.contains(ip("fd12:3456::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        assert!(!net.contains(ip("10.0.0.1")));
+        assert!(cidr("::1").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        // IPv4-mapped addresses match IPv4 networks, on either side
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.2.3.4")));
+        assert!(cidr("::ffff:10.2.3.4").contains(ip("10.2.3.4")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.2.3.4")));
+
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "example.com", ""] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("192.0.2.60:4711"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]:4711"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("::ffff:192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("[::ffff:192.0.2.60]:80"), Some(ip("192.0.2.60")));
+
+        for obfuscated in ["unknown", "_hidden", "_SEVKISEK", "", "[2001:db8::1", "192.0.2.60:port"] {
+            assert_eq!(parse_node(obfuscated), None, "{obfuscated:?}");
+        }
+
+        let chain = forwarded_for(&headers(&[
+            ("forwarded", r#"for=192.0.2.60;proto=http;by=203.0.113.43, For="[2001:db8:cafe::17]:4711""#),
+            ("forwarded", "for=unknown"),
+        ]));
+        assert_eq!(chain, Some(vec![Some(ip("192.0.2.60")), Some(ip("2001:db8:cafe::17")), None]));
+    }
+
+    #[test]
+    fn resolves_through_trusted_hops() {
+        let trusted = TrustedProxies::new(["10.0.0.0/8"]).unwrap();
+        let xff = |value: &str| headers(&[("x-forwarded-for", value)]);
+
+        // Untrusted peers' headers are ignored
+        assert_eq!(trusted.resolve(&tcp("203.0.113.1"), &xff("1.2.3.4")), Some(ip("203.0.113.1")));
+        assert_eq!(trusted.resolve(&tcp("::ffff:203.0.113.1"), &xff("1.2.3.4")), Some(ip("203.0.113.1")));
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &HeaderMap::new()), Some(ip("10.0.0.1")));
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("198.51.100.7")), Some(ip("198.51.100.7")));
+
+        // The client can put anything on the left; it stops at the first untrusted hop
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("1.2.3.4, 198.51.100.7")), Some(ip("198.51.100.7")));
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("10.9.9.9, 198.51.100.7, 10.0.0.2")), Some(ip("198.51.100.7")));
+        assert_eq!(
+            trusted.resolve(&tcp("10.0.0.1"), &headers(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "198.51.100.7, 10.0.0.2")])),
+            Some(ip("198.51.100.7")),
+        );
+        // Untrusted hop in the middle: nothing left of it counts, even if it's in our range
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("10.5.5.5, 198.51.100.7, 10.0.0.2")), Some(ip("198.51.100.7")));
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("1.2.3.4, garbage, 10.0.0.2")), Some(ip("10.0.0.2")));
+        // All trusted, so the leftmost is as far back as it goes
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &xff("10.0.0.3, 10.0.0.2")), Some(ip("10.0.0.3")));
+
+        // Only the configured header counts
+        let spoofed = headers(&[("x-real-ip", "1.2.3.4"), ("forwarded", "for=1.2.3.4"), ("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(trusted.resolve(&tcp("10.0.0.1"), &spoofed), Some(ip("198.51.100.7")));
+        let forwarded = trusted.clone().header(ForwardedHeader::Forwarded);
+        assert_eq!(forwarded.resolve(&tcp("10.0.0.1"), &spoofed), Some(ip("1.2.3.4")));
+        let real_ip = trusted.header(ForwardedHeader::XRealIp);
+        assert_eq!(real_ip.resolve(&tcp("10.0.0.1"), &spoofed), Some(ip("1.2.3.4")));
+
+        // Unix socket peers are trusted, but have no address of their own
+        let unix = Peer::Unix(UnixPeer { path: None, cred: None });
+        let trusted = TrustedProxies::default();
+        assert_eq!(trusted.resolve(&unix, &xff("1.2.3.4, 198.51.100.7")), Some(ip("198.51.100.7")));
+        assert_eq!(trusted.resolve(&unix, &HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,247 @@
+use axum::{extract, middleware, routing};
+use axum::http::{header, HeaderValue};
+use axum::response::IntoResponse;
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar};
+
+use crate::hex;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum CookieKeyError {
+    #[error("Failed to read {}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Failed to write {}", .0.display())]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("Invalid cookie key in {} (expected 128 hex digits)", .0)]
+    Invalid(String),
+    #[error("No cookie key in {}", .0)]
+    Empty(String),
+}
+
+/// The key new cookies are encrypted with, plus retired keys that are still accepted so
+/// rotating doesn't log everyone out. Keys are written as 128 hex digits.
+#[derive(Clone)]
+pub struct CookieKeys {
+    current: Key,
+    old: Arc<[Key]>,
+}
+impl CookieKeys {
+    pub fn new(current: Key) -> Self {
+        CookieKeys { current, old: Arc::new([]) }
+    }
+
+    /// Also accept cookies encrypted with these
+    pub fn with_old(self, old: impl IntoIterator<Item = Key>) -> Self {
+        CookieKeys { old: old.into_iter().collect(), ..self }
+    }
+
+    pub fn current(&self) -> &Key {
+        &self.current
+    }
+
+    pub fn old(&self) -> &[Key] {
+        &self.old
+    }
+
+    /// From the env var `var` if it's set, else from the key file at `path`
+    /// (see [`CookieKeys::load_file`])
+    pub fn load(var: &str, path: impl AsRef<Path>) -> Result<Self, CookieKeyError> {
+        match std::env::var(var) {
+            Ok(keys) if !keys.trim().is_empty() => Self::parse(&keys, &format!("${var}")),
+            _ => Self::load_file(path),
+        }
+    }
+
+    /// One key per line, current first; empty lines and `#` comments are ignored. Generates a
+    /// key and saves it (readable only by us) if the file doesn't exist yet.
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, CookieKeyError> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents, &path.display().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let keys = CookieKeys::new(Key::generate());
+                keys.save(path)?;
+                info!("Generated a new cookie key in {}", path.display());
+                Ok(keys)
+            },
+            Err(e) => Err(CookieKeyError::Read(path.into(), e)),
+        }
+    }
+
+    /// Put a freshly generated key at the front of the key file. The previous current key is
+    /// always kept, so cookies issued until now still work, plus up to `keep_old` of the keys
+    /// retired before it. Running servers pick it up on restart.
+    pub fn rotate_file(path: impl AsRef<Path>, keep_old: usize) -> Result<Self, CookieKeyError> {
+        let path = path.as_ref();
+        let previous = Self::load_file(path)?;
+        let old = std::iter::once(previous.current)
+            .chain(previous.old.iter().take(keep_old).cloned())
+            .collect::<Vec<_>>();
+        let keys = CookieKeys::new(Key::generate()).with_old(old);
+        keys.save(path)?;
+        info!("Rotated the cookie key in {}, {} old key(s) still accepted", path.display(), keys.old.len());
+        Ok(keys)
+    }
+
+    fn parse(keys: &str, source: &str) -> Result<Self, CookieKeyError> {
+        let mut keys = keys.lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split([',', ' ', '\t']))
+            .filter(|key| !key.is_empty())
+            .map(|key| decode_key(key).ok_or_else(|| CookieKeyError::Invalid(source.to_owned())));
+        let current = keys.next().ok_or_else(|| CookieKeyError::Empty(source.to_owned()))??;
+        Ok(CookieKeys::new(current).with_old(keys.collect::<Result<Vec<_>, _>>()?))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), CookieKeyError> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut contents = String::from("# Cookie keys, the first one is current\n");
+        for key in std::iter::once(&self.current).chain(self.old.iter()) {
+            contents += &encode_key(key);
+            contents.push('\n');
+        }
+        // Write next to it and rename, so a crash can't leave a truncated key file
+        let write_err = |e| CookieKeyError::Write(path.into(), e);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut file = std::fs::OpenOptions::new()
+            .write(true).create(true).truncate(true)
+            .mode(0o600)
+            .open(&temp)
+            .map_err(write_err)?;
+        file.write_all(contents.as_bytes()).and_then(|()| file.sync_all()).map_err(write_err)?;
+        std::fs::rename(&temp, path).map_err(write_err)
+    }
+
+    /// Decrypts `cookie` with whichever key it was encrypted with
+    pub fn decrypt(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        std::iter::once(&self.current).chain(self.old.iter())
+            .find_map(|key| PrivateCookieJar::new(key.clone()).decrypt(cookie.clone()))
+    }
+
+    fn upgrade_request(&self, req: &mut extract::Request) {
+        let mut upgraded = false;
+        let values = req.headers().get_all(header::COOKIE).iter()
+            .map(|value| match self.upgrade_header(value) {
+                Some(new) => {
+                    upgraded = true;
+                    new
+                },
+                None => value.clone(),
+            })
+            .collect::<Vec<_>>();
+        if !upgraded {
+            return;
+        }
+        req.headers_mut().remove(header::COOKIE);
+        for value in values {
+            req.headers_mut().append(header::COOKIE, value);
+        }
+    }
+
+    /// Replaces only the pairs that were re-encrypted, everything else stays as it was
+    fn upgrade_header(&self, value: &HeaderValue) -> Option<HeaderValue> {
+        let mut upgraded = false;
+        let value = value.to_str().ok()?.split(';')
+            .map(|pair| {
+                let start = pair.len() - pair.trim_start().len();
+                let end = pair.trim_end().len().max(start);
+                match self.upgrade_pair(&pair[start..end]) {
+                    Some(new) => {
+                        upgraded = true;
+                        format!("{}{}{}", &pair[..start], new, &pair[end..])
+                    },
+                    None => pair.to_owned(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        upgraded.then(|| HeaderValue::try_from(value).ok()).flatten()
+    }
+
+    /// `name=value` encrypted with the current key, if `pair` uses an old one
+    fn upgrade_pair(&self, pair: &str) -> Option<String> {
+        let cookie = Cookie::parse_encoded(pair.to_owned()).ok()?;
+        if PrivateCookieJar::new(self.current.clone()).decrypt(cookie.clone()).is_some() {
+            return None;
+        }
+        // Not ours, or not encrypted at all, if none of them can decrypt it
+        let old = self.old.iter()
+            .find_map(|key| PrivateCookieJar::new(key.clone()).decrypt(cookie.clone()))?;
+        Some(self.encrypt(old)?.encoded().to_string())
+    }
+
+    fn encrypt(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        let response = PrivateCookieJar::new(self.current.clone()).add(cookie).into_response();
+        let value = response.headers().get(header::SET_COOKIE)?.to_str().ok()?;
+        // Only the name and value are needed for the request
+        let cookie = Cookie::parse_encoded(value.to_owned()).ok()?;
+        Some(Cookie::new(cookie.name().to_owned(), cookie.value().to_owned()))
+    }
+}
+impl std::fmt::Debug for CookieKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CookieKeys {{ old: {} }}", self.old.len())
+    }
+}
+
+/// Re-encrypts request cookies that use an old key with the current one, so `PrivateCookieJar`
+/// and [`crate::session`] accept them. They're rewritten in the browser whenever the app sets
+/// them again. Add it after (i.e. outside of) anything reading cookies.
+pub fn cookie_keys_layer(keys: CookieKeys)
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    middleware::from_fn(move |mut req: extract::Request, next: middleware::Next| {
+        if !keys.old.is_empty() {
+            keys.upgrade_request(&mut req);
+        }
+        next.run(req)
+    })
+}
+
+fn encode_key(key: &Key) -> String {
+    hex::encode(key.master())
+}
+
+fn decode_key(key: &str) -> Option<Key> {
+    if key.len() != 128 {
+        return None;
+    }
+    Key::try_from(&hex::decode(key)?[..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_previous_key() {
+        let dir = std::env::temp_dir().join(format!("runtime-cookie-keys-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys");
+        let (first, second) = (Key::generate(), Key::generate());
+        CookieKeys::new(first.clone()).with_old([second]).save(&path).unwrap();
+
+        let rotated = CookieKeys::rotate_file(&path, 0).unwrap();
+        assert_eq!(rotated.old(), std::slice::from_ref(&first));
+        assert!(*rotated.current() != first);
+
+        let rotated_again = CookieKeys::rotate_file(&path, 1).unwrap();
+        assert_eq!(rotated_again.old(), [rotated.current().clone(), first]);
+        assert_eq!(CookieKeys::load_file(&path).unwrap().old().len(), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
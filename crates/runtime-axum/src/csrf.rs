@@ -0,0 +1,434 @@
+use axum::{extract, middleware, routing};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::IntoResponse;
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar, SameSite};
+
+use crate::hex;
+use crate::session::SessionHandle;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+const SECRET_LEN: usize = 32;
+
+/// Settings for [`csrf_layer`]
+#[derive(Clone)]
+pub struct CsrfConfig {
+    key: Key,
+    cookie_name: String,
+    field_name: String,
+    exempt: Vec<String>,
+    allowed_origins: Vec<String>,
+    max_form_size: usize,
+    secure: bool,
+}
+impl CsrfConfig {
+    /// Secrets kept in a cookie encrypted with `key` (usually [`crate::ServerState::cookie_key`])
+    pub fn new(key: Key) -> Self {
+        CsrfConfig {
+            key,
+            cookie_name: "csrf".into(),
+            field_name: "csrf_token".into(),
+            exempt: Vec::new(),
+            allowed_origins: Vec::new(),
+            max_form_size: 2 * 1024 * 1024,
+            secure: true,
+        }
+    }
+    /// Skip the checks for `prefix` and the paths below it, e.g. `/api` for routes that
+    /// authenticate with tokens instead of cookies (`/api` doesn't cover `/apikeys`). Paths
+    /// are as seen by the layer.
+    pub fn exempt(mut self, prefix: impl Into<String>) -> Self {
+        self.exempt.push(prefix.into());
+        self
+    }
+    /// Accept requests from this origin (like `https://app.example.com`) besides our own.
+    /// Our own is the scheme and `Host` of the request, so behind a proxy that rewrites `Host`
+    /// add the public origin here.
+    pub fn allowed_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+    /// `csrf` by default
+    pub fn cookie_name(self, name: impl Into<String>) -> Self {
+        CsrfConfig { cookie_name: name.into(), ..self }
+    }
+    /// Form field holding the token, `csrf_token` by default
+    pub fn field_name(self, name: impl Into<String>) -> Self {
+        CsrfConfig { field_name: name.into(), ..self }
+    }
+    /// Largest urlencoded form body searched for the token (2 MiB by default, same as axum's `Form`)
+    pub fn max_form_size(self, bytes: usize) -> Self {
+        CsrfConfig { max_form_size: bytes, ..self }
+    }
+    /// Whether the cookie is only sent over HTTPS (on by default, turn off for plain HTTP
+    /// development). Also the scheme our own origin is assumed to have, as behind a
+    /// TLS-terminating proxy requests don't say.
+    pub fn secure(self, secure: bool) -> Self {
+        CsrfConfig { secure, ..self }
+    }
+
+    fn load_secret(&self, headers: &HeaderMap) -> Option<[u8; SECRET_LEN]> {
+        let cookie = PrivateCookieJar::from_headers(headers, self.key.clone()).get(&self.cookie_name)?;
+        hex::decode(cookie.value())?.try_into().ok()
+    }
+
+    fn set_cookie(&self, secret: &[u8]) -> Option<HeaderValue> {
+        let cookie = Cookie::build((self.cookie_name.clone(), hex::encode(secret)))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax);
+        let response = PrivateCookieJar::new(self.key.clone()).add(cookie).into_response();
+        response.headers().get(header::SET_COOKIE).cloned()
+    }
+
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt.iter().any(|prefix| match path.strip_prefix(&prefix[..]) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+            None => false,
+        })
+    }
+
+    /// `Origin` must be us or allowed; without it, `Sec-Fetch-Site` mustn't say another site
+    fn check_origin(&self, req: &extract::Request) -> Result<(), &'static str> {
+        let headers = req.headers();
+        if let Some(origin) = headers.get(header::ORIGIN) {
+            let origin = origin.to_str().map_err(|_| "invalid Origin")?;
+            if self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+                return Ok(());
+            }
+            let host = req.uri().authority().map(|a| a.as_str())
+                .or_else(|| headers.get(header::HOST).and_then(|h| h.to_str().ok()))
+                .ok_or("cross-origin request")?;
+            let scheme = match req.uri().scheme_str() {
+                Some(scheme) => scheme,
+                None if self.secure => "https",
+                None => "http",
+            };
+            return match origin.eq_ignore_ascii_case(&format!("{scheme}://{host}")) {
+                true => Ok(()),
+                false => Err("cross-origin request"),
+            };
+        }
+        match headers.get("sec-fetch-site").map(|v| v.as_bytes()) {
+            Some(b"same-origin" | b"none") | None => Ok(()),
+            Some(_) => Err("cross-site request"),
+        }
+    }
+
+    /// Hands the request back if it passes
+    async fn check(&self, req: extract::Request, token: &CsrfToken) -> Result<extract::Request, &'static str> {
+        self.check_origin(&req)?;
+        let (given, req) = self.take_token(req).await?;
+        let given = given.ok_or("missing token")?;
+        match token.verify(&given) {
+            true => Ok(req),
+            false => Err("invalid token"),
+        }
+    }
+
+    /// From the header, else from an urlencoded form body, which is put back afterwards
+    async fn take_token(&self, req: extract::Request) -> Result<(Option<String>, extract::Request), &'static str> {
+        if let Some(token) = req.headers().get(CSRF_HEADER) {
+            let token = token.to_str().ok().map(str::to_owned);
+            return Ok((token, req));
+        }
+        let is_form = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+        if !is_form {
+            return Ok((None, req));
+        }
+        let (parts, body) = req.into_parts();
+        let bytes = axum::body::to_bytes(body, self.max_form_size).await
+            .map_err(|_| "form too large")?;
+        // Tokens are hex, so the value never needs decoding
+        let token = bytes.split(|b| *b == b'&')
+            .filter_map(|pair| {
+                let eq = pair.iter().position(|b| *b == b'=')?;
+                Some((&pair[..eq], &pair[eq + 1..]))
+            })
+            .find(|(name, _)| *name == self.field_name.as_bytes())
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+            .map(str::to_owned);
+        Ok((token, extract::Request::from_parts(parts, axum::body::Body::from(bytes))))
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+/// Rejects unsafe-method requests (POST, PUT, ...) with 403 unless they come from our own
+/// origin and carry the [`CsrfToken`], in the `X-CSRF-Token` header or the form field.
+/// Multipart forms need to send the header.
+///
+/// The secret behind the tokens is kept in the session when [`crate::session::session_layer`]
+/// is added after (i.e. outside of) this layer, so [`crate::session::Session::regenerate`]
+/// replaces it on login. Otherwise it lives in its own encrypted cookie, set once a token is
+/// first used.
+pub fn csrf_layer(config: CsrfConfig)
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    let config = Arc::new(config);
+    middleware::from_fn(move |mut req: extract::Request, next: middleware::Next| {
+        let config = Arc::clone(&config);
+        async move {
+            let secret = match req.extensions().get::<SessionHandle>() {
+                Some(session) => Secret::Session(session.clone()),
+                None => {
+                    let existing = config.load_secret(req.headers());
+                    Secret::Cookie {
+                        secret: Arc::new(existing.unwrap_or_else(rand::random)),
+                        existing: existing.is_some(),
+                        used: Arc::default(),
+                    }
+                },
+            };
+            let token = CsrfToken { secret, field_name: config.field_name.clone().into() };
+
+            if !is_safe(req.method()) && !config.is_exempt(req.uri().path()) {
+                req = match config.check(req, &token).await {
+                    Ok(req) => req,
+                    Err(reason) => {
+                        warn!("Rejected request failing the CSRF check: {}", reason);
+                        return (StatusCode::FORBIDDEN, "CSRF check failed").into_response();
+                    },
+                };
+            }
+
+            req.extensions_mut().insert(token.clone());
+            let mut response = next.run(req).await;
+            if let Secret::Cookie { secret, existing: false, used } = &token.secret {
+                if used.load(Ordering::Relaxed) {
+                    if let Some(cookie) = config.set_cookie(&secret[..]) {
+                        response.headers_mut().append(header::SET_COOKIE, cookie);
+                    }
+                }
+            }
+            response
+        }
+    })
+}
+
+/// The CSRF token for forms, from [`csrf_layer`]. Every call gives a differently masked token
+/// (so the secret can't be recovered from compressed responses); all of them are valid.
+#[derive(Clone)]
+pub struct CsrfToken {
+    secret: Secret,
+    field_name: Arc<str>,
+}
+
+#[derive(Clone)]
+enum Secret {
+    /// In the layer's own cookie; `existing` if it came with the request, else it's set once used
+    Cookie { secret: Arc<[u8; SECRET_LEN]>, existing: bool, used: Arc<AtomicBool> },
+    Session(SessionHandle),
+}
+
+impl CsrfToken {
+    /// For the `X-CSRF-Token` header or a form field
+    pub fn token(&self) -> String {
+        let secret = self.secret_for_use();
+        let mask: [u8; SECRET_LEN] = rand::random();
+        let masked = mask.iter().zip(secret.iter()).map(|(m, s)| m ^ s);
+        hex::encode(&mask.iter().copied().chain(masked).collect::<Vec<_>>())
+    }
+
+    /// Name of the form field the layer looks in
+    pub fn field_name(&self) -> &str {
+        &self.field_name
+    }
+
+    /// `<input type="hidden" ...>` to put inside a form
+    pub fn hidden_input(&self) -> String {
+        // Both parts are hex or a config value, nothing to escape in practice
+        format!(r#"<input type="hidden" name="{}" value="{}">"#, self.field_name, self.token())
+    }
+
+    /// What tokens are checked against, `None` before any was handed out
+    fn existing_secret(&self) -> Option<[u8; SECRET_LEN]> {
+        match &self.secret {
+            Secret::Cookie { secret, existing, .. } => existing.then_some(**secret),
+            Secret::Session(session) => hex::decode(&session.csrf_secret()?)?.try_into().ok(),
+        }
+    }
+
+    /// Creates and saves the secret on first use
+    fn secret_for_use(&self) -> [u8; SECRET_LEN] {
+        match &self.secret {
+            Secret::Cookie { secret, used, .. } => {
+                used.store(true, Ordering::Relaxed);
+                **secret
+            },
+            Secret::Session(session) => self.existing_secret().unwrap_or_else(|| {
+                let secret: [u8; SECRET_LEN] = rand::random();
+                session.set_csrf_secret(hex::encode(&secret));
+                secret
+            }),
+        }
+    }
+
+    fn verify(&self, token: &str) -> bool {
+        let Some(secret) = self.existing_secret() else { return false };
+        let Some(bytes) = hex::decode(token.trim()) else { return false };
+        if bytes.len() != 2 * SECRET_LEN {
+            return false;
+        }
+        let (mask, masked) = bytes.split_at(SECRET_LEN);
+        // Constant time, like AdminAuth
+        mask.iter().zip(masked).zip(secret.iter())
+            .fold(0, |acc, ((m, x), s)| acc | (m ^ x ^ s)) == 0
+    }
+}
+impl std::fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.token())
+    }
+}
+impl std::fmt::Debug for CsrfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CsrfToken")
+    }
+}
+
+#[axum::async_trait]
+impl<S> extract::FromRequestParts<S> for CsrfToken where S: Send + Sync {
+    type Rejection = (StatusCode, &'static str);
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CsrfToken>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "CSRF layer is missing"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CsrfConfig {
+        CsrfConfig::new(Key::generate())
+    }
+
+    fn cookie_token(secret: [u8; SECRET_LEN]) -> CsrfToken {
+        CsrfToken {
+            secret: Secret::Cookie { secret: Arc::new(secret), existing: true, used: Arc::default() },
+            field_name: "csrf_token".into(),
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> extract::Request {
+        let mut builder = axum::http::Request::builder().method(Method::POST).uri("/submit");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(axum::body::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn verifies_masked_tokens() {
+        let token = cookie_token(rand::random());
+        let given = token.token();
+        assert_ne!(given, token.token());
+        assert!(token.verify(&given));
+        assert!(token.verify(&token.token()));
+
+        assert!(!token.verify(""));
+        assert!(!token.verify(&given[..given.len() - 2]));
+        assert!(!token.verify(&format!("{given}00")));
+        assert!(!token.verify(&format!("{}zz", &given[..given.len() - 2])));
+        assert!(!token.verify(&format!("+{}", &given[1..])));
+        assert!(!token.verify(&cookie_token(rand::random()).token()));
+    }
+
+    #[test]
+    fn rejects_without_secret() {
+        let token = CsrfToken {
+            secret: Secret::Cookie { secret: Arc::new(rand::random()), existing: false, used: Arc::default() },
+            field_name: "csrf_token".into(),
+        };
+        assert!(!token.verify(&token.token()));
+    }
+
+    #[tokio::test]
+    async fn takes_token_from_form() {
+        let config = config();
+        let body = "name=a%20b&csrf_token=abc123&other=1";
+        let req = axum::http::Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded; charset=utf-8")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let (token, req) = config.take_token(req).await.unwrap();
+        assert_eq!(token.as_deref(), Some("abc123"));
+        // The handler still gets the whole body
+        let rest = axum::body::to_bytes(req.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&rest[..], body.as_bytes());
+
+        let req = request(&[(CSRF_HEADER.as_str(), "fromheader"), ("content-type", "application/x-www-form-urlencoded")]);
+        assert_eq!(config.take_token(req).await.unwrap().0.as_deref(), Some("fromheader"));
+
+        let req = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=x")
+            .body(axum::body::Body::from("csrf_token=abc123"))
+            .unwrap();
+        assert_eq!(config.take_token(req).await.unwrap().0, None);
+
+        let req = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(axum::body::Body::from("x".repeat(100)))
+            .unwrap();
+        assert!(config.max_form_size(10).take_token(req).await.is_err());
+    }
+
+    #[test]
+    fn checks_origin() {
+        let config = config().allowed_origin("https://app.example.com");
+        let check = |headers: &[(&str, &str)]| config.check_origin(&request(headers)).is_ok();
+
+        assert!(check(&[("host", "example.com"), ("origin", "https://example.com")]));
+        assert!(check(&[("host", "Example.com"), ("origin", "https://example.COM")]));
+        assert!(check(&[("host", "example.com:8443"), ("origin", "https://example.com:8443")]));
+        assert!(check(&[("host", "example.com"), ("origin", "https://app.example.com")]));
+        assert!(!check(&[("host", "example.com"), ("origin", "http://example.com")]));
+        assert!(!check(&[("host", "example.com"), ("origin", "https://evil.com")]));
+        assert!(!check(&[("host", "example.com"), ("origin", "https://example.com.evil.com")]));
+        assert!(!check(&[("host", "example.com"), ("origin", "null")]));
+        assert!(!check(&[("origin", "https://example.com")]));
+
+        let plain = self::config().secure(false);
+        assert!(plain.check_origin(&request(&[("host", "localhost:3000"), ("origin", "http://localhost:3000")])).is_ok());
+
+        assert!(check(&[("host", "example.com")]));
+        assert!(check(&[("host", "example.com"), ("sec-fetch-site", "same-origin")]));
+        assert!(check(&[("host", "example.com"), ("sec-fetch-site", "none")]));
+        assert!(!check(&[("host", "example.com"), ("sec-fetch-site", "same-site")]));
+        assert!(!check(&[("host", "example.com"), ("sec-fetch-site", "cross-site")]));
+    }
+
+    #[test]
+    fn exempts_on_segment_boundaries() {
+        let config = config().exempt("/api").exempt("/hooks/");
+        assert!(config.is_exempt("/api"));
+        assert!(config.is_exempt("/api/"));
+        assert!(config.is_exempt("/api/users"));
+        assert!(!config.is_exempt("/apikeys"));
+        assert!(!config.is_exempt("/ap"));
+        assert!(!config.is_exempt("/v1/api"));
+        assert!(config.is_exempt("/hooks/github"));
+        assert!(!config.is_exempt("/hooks"));
+        assert!(!config.is_exempt("/"));
+    }
+}
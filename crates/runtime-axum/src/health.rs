@@ -0,0 +1,60 @@
+use axum::{routing, extract, Router};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+
+use runtime::health::{HealthRegistry, HealthSnapshot};
+
+
+#[derive(serde::Serialize)]
+struct TaskReport {
+    name: &'static str,
+    state: &'static str,
+    restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct HealthReport {
+    status: &'static str,
+    tasks: Vec<TaskReport>,
+}
+impl HealthReport {
+    fn new(snapshot: HealthSnapshot) -> Self {
+        let status = if snapshot.shutting_down {
+            "draining"
+        } else if snapshot.is_ready() {
+            "ok"
+        } else {
+            "not ready"
+        };
+        HealthReport {
+            status,
+            tasks: snapshot.tasks.into_iter()
+                .map(|t| TaskReport {
+                    name: t.name,
+                    state: t.state.as_str(),
+                    restarts: t.restarts,
+                    last_error: t.last_error,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `/healthz` (liveness, always 200 while the process can answer) and `/readyz`
+/// (503 once shutdown begins or while any task isn't running), both with a JSON task report
+pub fn health_router<S>(health: HealthRegistry) -> Router<S>
+    where S: Clone + Send + Sync + 'static
+{
+    Router::new()
+        .route("/healthz", routing::get(|extract::State(health): extract::State<HealthRegistry>| async move {
+            axum::Json(HealthReport::new(health.snapshot()))
+        }))
+        .route("/readyz", routing::get(|extract::State(health): extract::State<HealthRegistry>| async move {
+            let snapshot = health.snapshot();
+            let status = if snapshot.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            (status, axum::Json(HealthReport::new(snapshot))).into_response()
+        }))
+        .with_state(health)
+}
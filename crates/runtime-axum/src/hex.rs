@@ -0,0 +1,36 @@
+// Lowercase hex for keys and tokens
+
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `None` unless it's an even number of hex digits
+pub(crate) fn decode(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign, like "+f"
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(encode(&[0, 0xab, 0xff]), "00abff");
+        assert_eq!(decode("00abff"), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(decode("00ABFF"), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(decode(""), Some(vec![]));
+    }
+
+    #[test]
+    fn rejects_non_hex() {
+        for bad in ["0", "abc", "+f", "-f", "0x", "zz", " f", "é0"] {
+            assert_eq!(decode(bad), None, "{bad:?}");
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use axum::{routing, extract, middleware};
 use axum::http::{HeaderName, HeaderValue};
 use tower_http::trace as tower_trace;
-use std::net::SocketAddr;
+use crate::client_ip::{self, ClientIp};
+use crate::request_id::RequestId;
 
 // TODO: better error messages
 // https://github.com/tokio-rs/axum/issues/1116
@@ -81,31 +82,25 @@ pub fn make_trace_layer()
     )
         .make_span_with(|request: &axum::http::Request<axum::body::Body>| {
             // Can't use extractors since this isn't async
-            let connect_info = request.extensions().get::<extract::ConnectInfo<SocketAddr>>()
-                .map(|c| c.0);
-            let headers = request.headers();
-            let agent = headers.get(axum::http::header::USER_AGENT);
+            // The real client behind our proxies if client_ip_layer ran, else whoever connected
+            let connect_info = request.extensions().get::<ClientIp>()
+                .map(|ip| ip.0.to_string())
+                .or_else(|| client_ip::peer(request.extensions()).map(|peer| peer.to_string()));
+            let agent = request.headers().get(axum::http::header::USER_AGENT);
+            let request_id = request.extensions().get::<RequestId>();
+            // Picked up by the OTLP exporter to continue the caller's trace
+            let traceparent = request.headers().get(TRACEPARENT).and_then(|v| v.to_str().ok());
 
-            // Because almost nothing in the tracing ecosystem supports optional / late-initialized fields
-            let span = match (connect_info, agent) {
-                (Some(ip), Some(agent)) => tracing::debug_span!(
-                    "request", method = %request.method(), uri = %request.uri(), version = ?request.version(),
-                    ip = %ip, useragent = ?agent,
-                ),
-                (Some(ip), None) => tracing::debug_span!(
-                    "request", method = %request.method(), uri = %request.uri(), version = ?request.version(),
-                    ip = %ip, useragent = tracing::field::Empty,
-                ),
-                (None, Some(agent)) => tracing::debug_span!(
-                    "request", method = %request.method(), uri = %request.uri(), version = ?request.version(),
-                    ip = tracing::field::Empty, useragent = ?agent,
-                ),
-                (None, None) => tracing::debug_span!(
-                    "request", method = %request.method(), uri = %request.uri(), version = ?request.version(),
-                    ip = tracing::field::Empty, useragent = tracing::field::Empty,
-                ),
-            };
-            span
+            // Almost nothing in the tracing ecosystem supports late-initialized fields, but a
+            // None value at creation just leaves the field out
+            tracing::debug_span!(
+                "request", method = %request.method(), uri = %request.uri(), version = ?request.version(),
+                ip = connect_info.map(tracing::field::display),
+                useragent = agent.map(tracing::field::debug),
+                request_id = request_id.map(tracing::field::display),
+                traceparent = traceparent.map(tracing::field::display),
+                otel.kind = "server",
+            )
         })
         .on_request(
             tower_trace::DefaultOnRequest::new()
@@ -117,3 +112,15 @@ pub fn make_trace_layer()
     custom_trace_layer
 }
 
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// Adds the current span's `traceparent` to an outgoing request, so the service it goes to
+/// continues our trace. Does nothing unless the OTLP exporter is set up.
+pub fn inject_traceparent(headers: &mut axum::http::HeaderMap) {
+    if let Some(context) = runtime::log::otlp::TraceContext::current() {
+        if let Ok(value) = HeaderValue::from_str(&context.to_traceparent()) {
+            headers.insert(TRACEPARENT, value);
+        }
+    }
+}
+
@@ -1,5 +1,5 @@
 
-#[allow(unused)]
+#[allow(unused_imports)]
 #[macro_use]
 extern crate tracing;
 
@@ -8,6 +8,16 @@ use std::sync::Arc;
 
 pub mod layers;
 pub mod server;
+pub mod health;
+pub mod tls;
+pub mod client_ip;
+pub mod request_id;
+pub mod admin;
+pub mod metrics;
+pub mod session;
+pub mod cookie_keys;
+pub mod csrf;
+mod hex;
 
 
 pub struct ServerState<T> {
@@ -30,7 +40,7 @@ impl<T> Clone for ServerState<T> {
 impl<T> std::ops::Deref for ServerState<T> {
     type Target = T;
     fn deref(&self) -> &T {
-        &*self.app
+        &self.app
     }
 }
 impl<T> ServerState<T> {
@@ -40,6 +50,12 @@ impl<T> ServerState<T> {
             app,
         }
     }
+
+    /// With the current key of `keys`; add [`cookie_keys::cookie_keys_layer`] so cookies
+    /// encrypted with the old ones keep working
+    pub fn with_keys(keys: &cookie_keys::CookieKeys, app: Arc<T>) -> Self {
+        Self::new(keys.current().clone(), app)
+    }
 }
 
 // pub async fn start_webserver<T>(
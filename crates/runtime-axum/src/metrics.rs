@@ -0,0 +1,103 @@
+use axum::{extract, middleware, routing, Router};
+use axum::http::header;
+
+use std::sync::Arc;
+
+use runtime::metrics::{Counter, Family, Gauge, Histogram, Registry};
+
+
+#[derive(Clone)]
+struct HttpMetrics {
+    requests: Arc<Family<Counter>>,
+    duration: Arc<Family<Histogram>>,
+    in_flight: Arc<Family<Gauge>>,
+}
+impl HttpMetrics {
+    fn new(registry: &Registry) -> Self {
+        HttpMetrics {
+            requests: registry.counter_vec(
+                "http_requests_total", "HTTP requests handled",
+                &["method", "route", "status"],
+            ),
+            duration: registry.histogram_vec(
+                "http_request_duration_seconds", "Time until the response headers were ready",
+                &["method", "route", "status"], runtime::metrics::DEFAULT_BUCKETS,
+            ),
+            in_flight: registry.gauge_vec(
+                "http_requests_in_flight", "HTTP requests being handled",
+                &["method", "route"],
+            ),
+        }
+    }
+}
+
+// Decrements even if the request future is dropped midway
+struct InFlight(Gauge);
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Records request counts, latencies and in-flight requests in the global registry, labelled
+/// by method, matched route (`unmatched` for the fallback, never the raw path) and status class
+/// like `2xx`. Add it with `Router::layer` so the matched route is known.
+pub fn metrics_layer()
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    let metrics = HttpMetrics::new(runtime::metrics::global());
+    middleware::from_fn(move |req: extract::Request, next: middleware::Next| {
+        let metrics = metrics.clone();
+        async move {
+            let method = method_label(req.method()).to_owned();
+            let route = req.extensions().get::<extract::MatchedPath>()
+                .map(|path| path.as_str().to_owned())
+                .unwrap_or_else(|| "unmatched".to_owned());
+
+            let gauge = metrics.in_flight.with(&[&method, &route]);
+            gauge.inc();
+            let in_flight = InFlight(gauge);
+            let start = std::time::Instant::now();
+            let response = next.run(req).await;
+            let elapsed = start.elapsed().as_secs_f64();
+            drop(in_flight);
+
+            let status = format!("{}xx", response.status().as_u16() / 100);
+            let labels = [&method[..], &route, &status];
+            metrics.requests.with(&labels).inc();
+            metrics.duration.with(&labels).observe(elapsed);
+            response
+        }
+    })
+}
+
+// Extension methods would let clients create any number of series
+fn method_label(method: &axum::http::Method) -> &str {
+    use axum::http::Method;
+    match *method {
+        Method::GET | Method::HEAD | Method::POST | Method::PUT | Method::DELETE
+        | Method::PATCH | Method::OPTIONS | Method::CONNECT | Method::TRACE => method.as_str(),
+        _ => "OTHER",
+    }
+}
+
+/// `/metrics` serving the global registry in the Prometheus text format
+pub fn metrics_router<S>() -> Router<S>
+    where S: Clone + Send + Sync + 'static
+{
+    Router::new()
+        .route("/metrics", routing::get(|| async {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+                runtime::metrics::global().render(),
+            )
+        }))
+}
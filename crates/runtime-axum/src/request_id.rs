@@ -0,0 +1,72 @@
+use axum::{extract, middleware, routing};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+
+use std::sync::Arc;
+
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Correlation ID of the current request, set by [`request_id_layer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(Arc<str>);
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// 32 random hex digits
+    pub fn generate() -> Self {
+        RequestId(format!("{:032x}", rand::random::<u128>()).into())
+    }
+
+    /// Accepts IDs from upstream proxies or clients only if they're reasonably short
+    /// and can't mess up log lines
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty() && value.len() <= 128
+            && value.bytes().all(|c| c.is_ascii_alphanumeric() || b"-_.:+/=".contains(&c));
+        valid.then(|| RequestId(value.into()))
+    }
+}
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Takes the request ID from `X-Request-Id` or generates one, and sends it back in the response.
+/// Add it after (i.e. outside of) [`crate::layers::make_trace_layer`] so the span records it.
+pub fn request_id_layer()
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    middleware::from_fn(|mut req: extract::Request, next: middleware::Next| async move {
+        let id = req.headers().get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        req.extensions_mut().insert(id.clone());
+
+        let mut response = next.run(req).await;
+        if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        response
+    })
+}
+
+#[axum::async_trait]
+impl<S> extract::FromRequestParts<S> for RequestId where S: Send + Sync {
+    type Rejection = (StatusCode, &'static str);
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<RequestId>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Request ID layer is missing"))
+    }
+}
@@ -1,28 +1,482 @@
 use axum::Router;
+use axum::extract::{self, ConnectInfo};
 
 use runtime::instrument;
 use runtime::utils::enclose;
 
+use crate::tls::Tls;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+
 use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Where [`run_server`] accepts connections
+pub enum Listen {
+    Bind(SocketAddr),
+    /// An already listening socket, e.g. from systemd socket activation
+    Listener(std::net::TcpListener),
+    /// Unix domain socket path; a stale socket file left behind by a previous run is replaced
+    Unix(PathBuf),
+    UnixListener(UnixListener),
+}
+impl From<SocketAddr> for Listen {
+    fn from(addr: SocketAddr) -> Self {
+        Listen::Bind(addr)
+    }
+}
+impl From<std::net::TcpListener> for Listen {
+    fn from(listener: std::net::TcpListener) -> Self {
+        Listen::Listener(listener)
+    }
+}
+impl From<UnixListener> for Listen {
+    fn from(listener: UnixListener) -> Self {
+        Listen::UnixListener(listener)
+    }
+}
+impl Listen {
+    /// Use a socket passed through `LISTEN_FDS` (by systemd, or by the process we're replacing
+    /// in an upgrade) if there are any, otherwise bind `addr`. The socket has to be named after
+    /// `addr` (with ':' replaced by '_') unless it's the only one passed.
+    pub fn systemd_or(addr: SocketAddr) -> Result<Self, std::io::Error> {
+        Ok(Self::passed(&listener_name(&addr.to_string()))?.unwrap_or(Listen::Bind(addr)))
+    }
+
+    /// Same as [`Listen::systemd_or`] for a Unix domain socket at `path`
+    pub fn systemd_or_unix(path: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let path = path.into();
+        Ok(Self::passed(&listener_name(&path.to_string_lossy()))?.unwrap_or(Listen::Unix(path)))
+    }
+
+    fn passed(name: &str) -> Result<Option<Self>, std::io::Error> {
+        let passed = runtime::systemd::listen_fds_passed();
+        if passed == 0 {
+            return Ok(None);
+        }
+        let (name, fd) = runtime::systemd::take_listen_fd(name)
+            .map(|fd| (name.to_owned(), fd))
+            .or_else(runtime::systemd::take_any_listen_fd)
+            .ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("None of the {passed} passed sockets is named {name:?} (FileDescriptorName={name})"),
+            ))?;
+        info!("Using passed listening socket {:?}", name);
+        Ok(Some(Self::from_fd(fd)))
+    }
+
+    fn from_fd(fd: OwnedFd) -> Self {
+        // Only succeeds for AF_UNIX sockets
+        let listener = UnixListener::from(fd);
+        if listener.local_addr().is_ok() {
+            Listen::UnixListener(listener)
+        } else {
+            Listen::Listener(OwnedFd::from(listener).into())
+        }
+    }
+
+    fn into_listener(self) -> Result<Bound, std::io::Error> {
+        match self {
+            Listen::Bind(addr) => Ok(Bound::Tcp(std::net::TcpListener::bind(addr)?)),
+            Listen::Listener(listener) => Ok(Bound::Tcp(listener)),
+            Listen::Unix(path) => {
+                // During an upgrade the file belongs to the process we're replacing, which
+                // still serves on it
+                if !runtime::upgrade::is_upgrade_child() {
+                    remove_stale_socket(&path)?;
+                }
+                Ok(Bound::Unix(UnixListener::bind(path)?))
+            },
+            Listen::UnixListener(listener) => Ok(Bound::Unix(listener)),
+        }
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+enum Bound {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+impl Bound {
+    fn name(&self) -> Result<String, std::io::Error> {
+        match self {
+            Bound::Tcp(listener) => Ok(listener_name(&listener.local_addr()?.to_string())),
+            Bound::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().map(|p| p.to_string_lossy()).unwrap_or_default();
+                Ok(listener_name(&path))
+            },
+        }
+    }
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        use std::os::fd::AsFd;
+        match self {
+            Bound::Tcp(listener) => listener.as_fd(),
+            Bound::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+/// Name a listener gets in `LISTEN_FDNAMES`, which is ':'-separated so can't hold the address as is
+fn listener_name(addr: &str) -> String {
+    addr.replace(':', "_")
+}
+
+
+/// The other end of a connection, available to handlers as `ConnectInfo<Peer>` whichever kind of
+/// socket it came in on. TCP connections also get the usual `ConnectInfo<SocketAddr>`.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix(UnixPeer),
+}
+#[derive(Debug, Clone)]
+pub struct UnixPeer {
+    /// Usually `None`, since clients rarely bind their end
+    pub path: Option<PathBuf>,
+    pub cred: Option<tokio::net::unix::UCred>,
+}
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix(UnixPeer { path: Some(path), .. }) => write!(f, "unix:{}", path.display()),
+            Peer::Unix(UnixPeer { cred: Some(cred), .. }) => match cred.pid() {
+                Some(pid) => write!(f, "unix:pid={pid}"),
+                None => write!(f, "unix:uid={}", cred.uid()),
+            },
+            Peer::Unix(_) => write!(f, "unix"),
+        }
+    }
+}
+
 
 pub async fn run_server(
     cancel: tokio_util::sync::CancellationToken,
-    bind: SocketAddr,
+    bind: impl Into<Listen>,
+    app: Router,
+) -> Result<(), std::io::Error> {
+    Server::new(app).listen(bind).run(cancel).await
+}
+
+/// Like [`run_server`], but terminating TLS. Pass [`Tls::reloader`] to [`runtime::run`]
+/// to pick up renewed certificates on SIGHUP.
+pub async fn run_tls_server(
+    cancel: tokio_util::sync::CancellationToken,
+    bind: impl Into<Listen>,
     app: Router,
+    tls: &Tls,
 ) -> Result<(), std::io::Error> {
-    let handle = axum_server::Handle::new();
+    Server::new(app).listen(bind).tls(tls).run(cancel).await
+}
+
+/// Serve `app` on all of `binds` at once until `cancel` fires, see [`Server::tls`] for `tls`
+pub async fn run_servers(
+    cancel: tokio_util::sync::CancellationToken,
+    binds: impl IntoIterator<Item = Listen>,
+    app: Router,
+    tls: Option<&Tls>,
+) -> Result<(), std::io::Error> {
+    let mut server = Server::new(app);
+    for bind in binds {
+        server = server.listen(bind);
+    }
+    if let Some(tls) = tls {
+        server = server.tls(tls);
+    }
+    server.run(cancel).await
+}
+
+
+/// Serves a `Router` on one or more listeners, and drains connections on shutdown
+pub struct Server {
+    app: Router,
+    binds: Vec<Listen>,
+    tls: Option<RustlsConfig>,
+    drain_timeout: Duration,
+    drain_log_interval: Duration,
+}
+impl Server {
+    pub fn new(app: Router) -> Self {
+        Server {
+            app,
+            binds: Vec::new(),
+            tls: None,
+            drain_timeout: Duration::from_secs(8),
+            drain_log_interval: Duration::from_secs(1),
+        }
+    }
+
+    pub fn listen(mut self, bind: impl Into<Listen>) -> Self {
+        self.binds.push(bind.into());
+        self
+    }
+
+    /// Terminate TLS on the TCP listeners. Unix sockets are meant for a local reverse proxy
+    /// and always serve plain HTTP.
+    pub fn tls(mut self, tls: &Tls) -> Self {
+        self.tls = Some(tls.rustls_config());
+        self
+    }
+
+    /// How long connections get to finish after shutdown starts before they're closed (8s by default)
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// How often to log the number of remaining connections and requests while draining (every 1s by default)
+    pub fn drain_log_interval(mut self, interval: Duration) -> Self {
+        self.drain_log_interval = interval;
+        self
+    }
+
+    pub async fn run(self, cancel: tokio_util::sync::CancellationToken) -> Result<(), std::io::Error> {
+        let Server { app, binds, tls, drain_timeout, drain_log_interval } = self;
+        let handle = axum_server::Handle::new();
+        let drain = Drain::default();
+
+        let app = app
+            .layer(axum::middleware::from_fn_with_state(drain.clone(), count_request))
+            .layer(axum::Extension(GoingAway(drain.clone())));
+
+        let shutdown_task = tokio::task::spawn(instrument!("shutdown task"; enclose!([clone handle, clone drain] async move {
+            cancel.cancelled().await;
+
+            info!("Attempting graceful webserver shutdown with {}s timeout", drain_timeout.as_secs_f32());
+            drain.begin(drain_timeout);
+            handle.graceful_shutdown(Some(drain_timeout));
+
+            let mut interval = tokio::time::interval(drain_log_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let connections = handle.connection_count() + drain.0.unix_connections.load(Ordering::Relaxed);
+                let requests = drain.0.requests.load(Ordering::Relaxed);
+                if connections == 0 && requests == 0 {
+                    break;
+                }
+                let left = drain.deadline().map(|d| d.saturating_duration_since(tokio::time::Instant::now()));
+                info!(
+                    "Draining: {} connection(s), {} request(s) in flight, {:.1}s left",
+                    connections, requests, left.unwrap_or_default().as_secs_f32(),
+                );
+            }
+        })));
+
+        // Bind everything before serving anything, so a bad address fails the whole server
+        let listeners = binds.into_iter()
+            .map(Listen::into_listener)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut servers = tokio::task::JoinSet::new();
+        let mut registrations = Vec::new();
+        for listener in listeners {
+            // Keep the socket available to hand over to a new process on upgrade
+            registrations.push(runtime::upgrade::register_listener(listener.name()?, listener.as_fd())?);
+
+            match listener {
+                Bound::Tcp(listener) => {
+                    let server = axum_server::from_tcp(listener).handle(handle.clone());
+                    let service = app.clone()
+                        .layer(axum::middleware::map_request(tcp_peer))
+                        .into_make_service_with_connect_info::<SocketAddr>();
+                    match tls.clone() {
+                        None => servers.spawn(async move { server.serve(service).await }),
+                        Some(config) => servers.spawn(async move {
+                            server.acceptor(RustlsAcceptor::new(config)).serve(service).await
+                        }),
+                    };
+                },
+                Bound::Unix(listener) => {
+                    servers.spawn(serve_unix(listener, app.clone(), drain.clone()));
+                },
+            }
+        }
+
+        // Connections queue up on the bound listeners until the servers get to them
+        runtime::task::notify_ready();
+
+        let result = async {
+            while let Some(result) = servers.join_next().await {
+                // Dropping the rest aborts them
+                result.map_err(std::io::Error::other)??;
+            }
+            Ok(())
+        }.await;
+        shutdown_task.abort();
+        result
+    }
+}
+
+
+#[derive(Default)]
+struct DrainInner {
+    started: tokio_util::sync::CancellationToken,
+    deadline: std::sync::OnceLock<tokio::time::Instant>,
+    requests: AtomicUsize,
+    unix_connections: AtomicUsize,
+}
+#[derive(Clone, Default)]
+struct Drain(Arc<DrainInner>);
+impl Drain {
+    fn begin(&self, timeout: Duration) {
+        self.0.deadline.get_or_init(|| tokio::time::Instant::now() + timeout);
+        self.0.started.cancel();
+    }
+    fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.0.deadline.get().copied()
+    }
+}
+
+/// Decrements a counter when dropped, so cancelled requests are counted out too
+struct CountGuard<'a>(&'a AtomicUsize);
+impl<'a> CountGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        CountGuard(counter)
+    }
+}
+impl Drop for CountGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn count_request(
+    extract::State(drain): extract::State<Drain>,
+    request: extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let _guard = CountGuard::new(&drain.0.requests);
+    next.run(request).await
+}
+
+/// Lets long-lived handlers like WebSockets find out the server started draining, so they can
+/// say goodbye before their connection is cut at the drain deadline. Never fires when the
+/// `Router` isn't run by [`Server`].
+///
+/// ```ignore
+/// tokio::select! {
+///     msg = socket.recv() => ...,
+///     _ = going_away.notified() => {
+///         socket.send(GoingAway::close_message()).await.ok();
+///     },
+/// }
+/// ```
+#[derive(Clone)]
+pub struct GoingAway(Drain);
+impl GoingAway {
+    /// Resolves once shutdown begins
+    pub async fn notified(&self) {
+        self.0.0.started.cancelled().await
+    }
+    pub fn is_going_away(&self) -> bool {
+        self.0.0.started.is_cancelled()
+    }
+    /// When open connections will be closed, once shutdown has begun
+    pub fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.0.deadline()
+    }
+
+    /// A close frame with status 1001 "going away"
+    pub fn close_message() -> axum::extract::ws::Message {
+        axum::extract::ws::Message::Close(Some(axum::extract::ws::CloseFrame {
+            code: axum::extract::ws::close_code::AWAY,
+            reason: "server shutting down".into(),
+        }))
+    }
+}
+
+#[axum::async_trait]
+impl<S> extract::FromRequestParts<S> for GoingAway where S: Send + Sync {
+    type Rejection = std::convert::Infallible;
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<GoingAway>().cloned().unwrap_or_else(|| GoingAway(Drain::default())))
+    }
+}
+
+
+async fn tcp_peer(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: extract::Request,
+) -> extract::Request {
+    request.extensions_mut().insert(ConnectInfo(Peer::Tcp(addr)));
+    request
+}
+
+// axum_server only does TCP, so Unix sockets get a plain hyper accept loop with the same
+// shutdown behaviour
+async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    drain: Drain,
+) -> Result<(), std::io::Error> {
+    use tower::ServiceExt;
+
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let mut connections = tokio::task::JoinSet::new();
 
-    tokio::task::spawn(instrument!("shutdown task"; enclose!([clone handle] async move {
-        cancel.cancelled().await;
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of fds, don't spin on it
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                },
+            },
+            Some(_) = connections.join_next() => continue,
+            _ = drain.0.started.cancelled() => break,
+        };
 
-        let timeout = std::time::Duration::from_secs(8);
-        info!("Attempting graceful webserver shutdown with {}s timeout", timeout.as_secs_f32());
-        handle.graceful_shutdown(Some(timeout));
-    })));
+        let peer = Peer::Unix(UnixPeer {
+            path: addr.as_pathname().map(Into::into),
+            cred: stream.peer_cred().ok(),
+        });
+        let service = app.clone().map_request(move |mut request: extract::Request<hyper::body::Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(peer.clone()));
+            request
+        });
+        let drain = drain.clone();
+        connections.spawn(async move {
+            let _guard = CountGuard::new(&drain.0.unix_connections);
+            let builder = hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(
+                hyper_util::rt::TokioIo::new(stream),
+                hyper_util::service::TowerToHyperService::new(service),
+            );
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = drain.0.started.cancelled() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                },
+            };
+            if let Err(e) = result {
+                debug!("Connection error: {}", e);
+            }
+        });
+    }
 
-    axum_server::bind(bind)
-        .handle(handle)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+    let deadline = drain.deadline().unwrap_or_else(tokio::time::Instant::now);
+    if tokio::time::timeout_at(deadline, async { while connections.join_next().await.is_some() {} }).await.is_err() {
+        warn!("Closing {} unix socket connection(s) that didn't finish in time", connections.len());
+    }
     Ok(())
 }
@@ -0,0 +1,456 @@
+use axum::{extract, middleware, routing};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar, SameSite};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+
+/// Server-side storage for sessions that don't fit in their cookie. The cookie then only holds
+/// the (encrypted) session ID.
+#[axum::async_trait]
+pub trait SessionStore: Send + Sync + 'static {
+    /// The data saved under `id`, `None` if there's none or it expired
+    async fn load(&self, id: &str) -> std::io::Result<Option<String>>;
+    async fn save(&self, id: &str, data: &str, expires: SystemTime) -> std::io::Result<()>;
+    async fn remove(&self, id: &str) -> std::io::Result<()>;
+}
+
+/// Sessions in a `HashMap`, lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (String, SystemTime)>>,
+    saves: AtomicU64,
+}
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget expired sessions; also happens every 1024 saves
+    pub fn remove_expired(&self) {
+        let now = SystemTime::now();
+        self.sessions.lock().unwrap().retain(|_, (_, expires)| *expires > now);
+    }
+}
+#[axum::async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str) -> std::io::Result<Option<String>> {
+        Ok(self.sessions.lock().unwrap().get(id)
+            .filter(|(_, expires)| *expires > SystemTime::now())
+            .map(|(data, _)| data.clone()))
+    }
+    async fn save(&self, id: &str, data: &str, expires: SystemTime) -> std::io::Result<()> {
+        if self.saves.fetch_add(1, Ordering::Relaxed) % 1024 == 1023 {
+            self.remove_expired();
+        }
+        self.sessions.lock().unwrap().insert(id.to_owned(), (data.to_owned(), expires));
+        Ok(())
+    }
+    async fn remove(&self, id: &str) -> std::io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+/// One JSON file per session in a directory, survives restarts
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredSession {
+    /// Unix seconds
+    expires: u64,
+    data: String,
+}
+
+impl FileStore {
+    /// Keeps sessions in `<dir>/<id>.json`, creating `dir` if needed
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, id: &str) -> std::io::Result<PathBuf> {
+        // IDs only ever come out of our own encrypted cookies, but they do end up in a path
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid session ID"));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Delete files of expired sessions, returning how many there were. Nothing does this
+    /// automatically, so schedule it somewhere.
+    pub async fn remove_expired(&self) -> std::io::Result<usize> {
+        let now = unix_secs(SystemTime::now());
+        let mut removed = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let expired = match tokio::fs::read(&path).await {
+                Ok(contents) => serde_json::from_slice::<StoredSession>(&contents).map_or(true, |s| s.expires <= now),
+                Err(_) => false,
+            };
+            if expired && tokio::fs::remove_file(&path).await.is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+#[axum::async_trait]
+impl SessionStore for FileStore {
+    async fn load(&self, id: &str) -> std::io::Result<Option<String>> {
+        let contents = match tokio::fs::read(self.path(id)?).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let stored: StoredSession = serde_json::from_slice(&contents)?;
+        Ok((stored.expires > unix_secs(SystemTime::now())).then_some(stored.data))
+    }
+    async fn save(&self, id: &str, data: &str, expires: SystemTime) -> std::io::Result<()> {
+        let path = self.path(id)?;
+        let contents = serde_json::to_vec(&StoredSession { expires: unix_secs(expires), data: data.to_owned() })?;
+        // Readers never see a half-written file
+        let temp = path.with_extension("json.tmp");
+        tokio::fs::write(&temp, contents).await?;
+        tokio::fs::rename(&temp, &path).await
+    }
+    async fn remove(&self, id: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(id)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn new_session_id() -> String {
+    rand::random::<[u8; 32]>().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+
+#[derive(Debug, thiserror::Error)]
+enum SessionError {
+    #[error("Session store failed")]
+    Store(#[from] std::io::Error),
+    #[error("Session cookie would take {0} bytes, over the limit of {1}, and there's no session store")]
+    TooLarge(usize, usize),
+}
+
+/// What's in the encrypted cookie: the session itself, or where the store keeps it
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Envelope {
+    /// Unix seconds when the cookie was written, for rotation
+    iat: u64,
+    /// Unix seconds, so an old cookie can't be replayed forever
+    exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Hex secret of [`crate::csrf`] tokens, once one was used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    csrf: Option<String>,
+}
+
+/// Settings for [`session_layer`]
+#[derive(Clone)]
+pub struct SessionConfig {
+    key: Key,
+    store: Option<Arc<dyn SessionStore>>,
+    cookie_name: String,
+    ttl: Duration,
+    rotate_after: Duration,
+    max_cookie_size: usize,
+    secure: bool,
+}
+impl SessionConfig {
+    /// Sessions encrypted with `key` (usually [`crate::ServerState::cookie_key`]), without a store
+    pub fn new(key: Key) -> Self {
+        SessionConfig {
+            key,
+            store: None,
+            cookie_name: "session".into(),
+            ttl: Duration::from_secs(7 * 24 * 3600),
+            rotate_after: Duration::from_secs(3600),
+            max_cookie_size: 4000,
+            secure: true,
+        }
+    }
+    /// Where sessions go when they don't fit in the cookie
+    pub fn store(self, store: impl SessionStore) -> Self {
+        SessionConfig { store: Some(Arc::new(store)), ..self }
+    }
+    /// `session` by default
+    pub fn cookie_name(self, name: impl Into<String>) -> Self {
+        SessionConfig { cookie_name: name.into(), ..self }
+    }
+    /// How long a session lasts after it was last written or rotated (7 days by default)
+    pub fn ttl(self, ttl: Duration) -> Self {
+        SessionConfig { ttl, ..self }
+    }
+    /// Rewrite the cookie with a fresh expiry once it's this old, so active users stay logged in (1 hour by default)
+    pub fn rotate_after(self, interval: Duration) -> Self {
+        SessionConfig { rotate_after: interval, ..self }
+    }
+    /// Largest `Set-Cookie` header before the session moves to the store (4000 bytes by default,
+    /// browsers give up somewhere above 4096)
+    pub fn max_cookie_size(self, bytes: usize) -> Self {
+        SessionConfig { max_cookie_size: bytes, ..self }
+    }
+    /// Whether the cookie is only sent over HTTPS (on by default, turn off for plain HTTP development)
+    pub fn secure(self, secure: bool) -> Self {
+        SessionConfig { secure, ..self }
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build((self.cookie_name.clone(), value))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::try_from(self.ttl).unwrap_or(time::Duration::MAX))
+            .build()
+    }
+
+    /// The `Set-Cookie` value for the encrypted envelope
+    fn encrypted(&self, envelope: &Envelope) -> HeaderValue {
+        let value = serde_json::to_string(envelope).unwrap_or_default();
+        let response = PrivateCookieJar::new(self.key.clone()).add(self.cookie(value)).into_response();
+        response.headers().get(header::SET_COOKIE).cloned().expect("cookie jar sets the cookie")
+    }
+
+    async fn load(&self, headers: &HeaderMap) -> Result<SessionState, SessionError> {
+        let now = unix_secs(SystemTime::now());
+        let envelope = PrivateCookieJar::from_headers(headers, self.key.clone())
+            .get(&self.cookie_name)
+            .and_then(|cookie| serde_json::from_str::<Envelope>(cookie.value()).ok())
+            .filter(|envelope| envelope.exp > now);
+        let Some(envelope) = envelope else {
+            return Ok(SessionState::default());
+        };
+
+        let (data, id) = match (envelope.data, envelope.id, &self.store) {
+            (Some(data), _, _) => (data, None),
+            (None, Some(id), Some(store)) => match store.load(&id).await? {
+                Some(data) => (serde_json::from_str(&data).unwrap_or_default(), Some(id)),
+                // Expired or removed, the cookie is all that's left
+                None => (serde_json::Value::Null, None),
+            },
+            _ => (serde_json::Value::Null, None),
+        };
+        Ok(SessionState { data, id, issued: Some(envelope.iat), csrf: envelope.csrf, ..Default::default() })
+    }
+
+    /// The cookie to send back, if anything changed
+    async fn save(&self, mut state: SessionState) -> Result<Option<HeaderValue>, SessionError> {
+        if state.destroy || (state.regenerate && state.id.is_some()) {
+            if let (Some(id), Some(store)) = (state.id.take(), &self.store) {
+                store.remove(&id).await?;
+            }
+        }
+        if state.destroy {
+            let removal = Cookie::build((self.cookie_name.clone(), "")).path("/").removal().build();
+            return Ok(state.issued.is_some().then(|| HeaderValue::try_from(removal.to_string()).ok()).flatten());
+        }
+
+        let now = SystemTime::now();
+        let rotate = state.issued.is_some_and(|iat| unix_secs(now).saturating_sub(iat) >= self.rotate_after.as_secs());
+        if !state.changed && !state.regenerate && !rotate {
+            return Ok(None);
+        }
+
+        let expires = now + self.ttl;
+        let mut envelope = Envelope {
+            iat: unix_secs(now),
+            exp: unix_secs(expires),
+            data: Some(state.data),
+            id: None,
+            csrf: state.csrf,
+        };
+        let cookie = self.encrypted(&envelope);
+        let size = cookie.len();
+        if size <= self.max_cookie_size {
+            // Small enough again, the stored copy isn't needed anymore
+            if let (Some(id), Some(store)) = (&state.id, &self.store) {
+                store.remove(id).await?;
+            }
+            return Ok(Some(cookie));
+        }
+
+        let Some(store) = &self.store else {
+            return Err(SessionError::TooLarge(size, self.max_cookie_size));
+        };
+        let id = state.id.unwrap_or_else(new_session_id);
+        let data = serde_json::to_string(&envelope.data.take()).unwrap_or_default();
+        store.save(&id, &data, expires).await?;
+        envelope.id = Some(id);
+        Ok(Some(self.encrypted(&envelope)))
+    }
+}
+
+
+#[derive(Default)]
+struct SessionState {
+    /// Null for a new session
+    data: serde_json::Value,
+    /// Where the store keeps the session, if it does
+    id: Option<String>,
+    /// When the cookie that came in was written
+    issued: Option<u64>,
+    csrf: Option<String>,
+    changed: bool,
+    regenerate: bool,
+    destroy: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct SessionHandle(Arc<Mutex<SessionState>>);
+impl SessionHandle {
+    pub(crate) fn csrf_secret(&self) -> Option<String> {
+        self.0.lock().unwrap().csrf.clone()
+    }
+    pub(crate) fn set_csrf_secret(&self, secret: String) {
+        let mut state = self.0.lock().unwrap();
+        state.csrf = Some(secret);
+        state.changed = true;
+    }
+}
+
+/// Loads the session before the request and writes its cookie (and store entry) after,
+/// if the handler changed it or the cookie is due for rotation
+pub fn session_layer(config: SessionConfig)
+-> impl tower::Layer<
+        routing::Route,
+        Service = impl tower::Service<
+            axum::http::Request<axum::body::Body>,
+            Response = impl axum::response::IntoResponse,
+            Error = impl Into<std::convert::Infallible>,
+            Future = impl Send,
+        > + Clone
+    > + Clone
+{
+    let config = Arc::new(config);
+    middleware::from_fn(move |mut req: extract::Request, next: middleware::Next| {
+        let config = Arc::clone(&config);
+        async move {
+            let state = match config.load(req.headers()).await {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("Loading session failed: {}", runtime::utils::format_error_disp(&e));
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable").into_response();
+                },
+            };
+            let handle = SessionHandle(Arc::new(Mutex::new(state)));
+            req.extensions_mut().insert(handle.clone());
+
+            let mut response = next.run(req).await;
+            let state = std::mem::take(&mut *handle.0.lock().unwrap());
+            match config.save(state).await {
+                Ok(Some(cookie)) => {
+                    response.headers_mut().append(header::SET_COOKIE, cookie);
+                    response
+                },
+                Ok(None) => response,
+                Err(e) => {
+                    error!("Saving session failed: {}", runtime::utils::format_error_disp(&e));
+                    (StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable").into_response()
+                },
+            }
+        }
+    })
+}
+
+/// The session set up by [`session_layer`], as `T` (its `Default` when new or when the stored
+/// data doesn't deserialize anymore). Changes made through [`Session::set`] and
+/// [`Session::modify`] are saved after the handler returns.
+pub struct Session<T> {
+    data: T,
+    handle: SessionHandle,
+}
+impl<T: serde::Serialize> Session<T> {
+    pub fn set(&mut self, data: T) {
+        self.data = data;
+        self.write();
+    }
+
+    pub fn modify<R>(&mut self, f: impl FnOnce(&mut T) -> R) -> R {
+        let result = f(&mut self.data);
+        self.write();
+        result
+    }
+
+    /// Issue the session under a new identity, keeping its data. Call this after login or any
+    /// other privilege change, so a session planted or leaked before doesn't carry over. Only
+    /// sessions in the store can be revoked; an old cookie-only session stays valid until it expires.
+    /// CSRF tokens handed out before stop working, so call it before rendering new ones.
+    pub fn regenerate(&mut self) {
+        let mut state = self.handle.0.lock().unwrap();
+        state.csrf = None;
+        state.regenerate = true;
+        state.destroy = false;
+    }
+
+    /// Forget the session and remove its cookie, e.g. on logout
+    pub fn destroy(self) {
+        let mut state = self.handle.0.lock().unwrap();
+        state.data = serde_json::Value::Null;
+        state.destroy = true;
+    }
+
+    fn write(&self) {
+        match serde_json::to_value(&self.data) {
+            Ok(data) => {
+                let mut state = self.handle.0.lock().unwrap();
+                state.data = data;
+                state.changed = true;
+                state.destroy = false;
+            },
+            Err(e) => error!("Session data doesn't serialize: {}", e),
+        }
+    }
+}
+impl<T> std::ops::Deref for Session<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+#[axum::async_trait]
+impl<S, T> extract::FromRequestParts<S> for Session<T>
+    where S: Send + Sync, T: serde::de::DeserializeOwned + Default + Send
+{
+    type Rejection = (StatusCode, &'static str);
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let handle = parts.extensions.get::<SessionHandle>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Session layer is missing"))?;
+        let data = {
+            let state = handle.0.lock().unwrap();
+            match &state.data {
+                serde_json::Value::Null => T::default(),
+                data => T::deserialize(data).unwrap_or_else(|e| {
+                    debug!("Starting over with a session that doesn't deserialize: {}", e);
+                    T::default()
+                }),
+            }
+        };
+        Ok(Session { data, handle })
+    }
+}
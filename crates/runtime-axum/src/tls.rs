@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use runtime::reload::{reloader, BoxedReload};
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read {}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("No certificates found in {}", .0.display())]
+    NoCertificate(PathBuf),
+    #[error("No private key found in {}", .0.display())]
+    NoKey(PathBuf),
+    #[error("Invalid certificate or key in {}", .0.display())]
+    Invalid(PathBuf, #[source] rustls::Error),
+    #[error("Failed to set up TLS")]
+    Config(#[source] rustls::Error),
+}
+
+
+#[derive(Debug, Clone)]
+struct CertFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+impl CertFiles {
+    fn load(&self, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, TlsError> {
+        let read = |path: &Path| std::fs::read(path).map_err(|e| TlsError::Read(path.into(), e));
+
+        let cert_pem = read(&self.cert)?;
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..])
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TlsError::Read(self.cert.clone(), e))?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificate(self.cert.clone()));
+        }
+
+        let key_pem = read(&self.key)?;
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])
+            .map_err(|e| TlsError::Read(self.key.clone(), e))?
+            .ok_or_else(|| TlsError::NoKey(self.key.clone()))?;
+
+        let certified = CertifiedKey::from_der(certs, key, provider)
+            .map_err(|e| TlsError::Invalid(self.key.clone(), e))?;
+        Ok(Arc::new(certified))
+    }
+}
+
+/// Certificate and key PEM files to serve, with optional per-hostname certificates picked by SNI
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    default: CertFiles,
+    sni: Vec<(String, CertFiles)>,
+}
+impl TlsSettings {
+    /// `cert` holds the full chain, leaf first. Used for clients that don't send SNI
+    /// or ask for a hostname without its own certificate.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsSettings {
+            default: CertFiles { cert: cert.into(), key: key.into() },
+            sni: Vec::new(),
+        }
+    }
+
+    /// Serve a different certificate for `hostname`, which may be a wildcard like `*.example.com`
+    pub fn sni(mut self, hostname: &str, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.sni.push((hostname.to_ascii_lowercase(), CertFiles { cert: cert.into(), key: key.into() }));
+        self
+    }
+
+    pub fn load(self) -> Result<Tls, TlsError> {
+        let config = self.server_config()?;
+        Ok(Tls {
+            settings: Arc::new(self),
+            config: RustlsConfig::from_config(config),
+        })
+    }
+
+    fn server_config(&self) -> Result<Arc<rustls::ServerConfig>, TlsError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = SniResolver {
+            default: self.default.load(&provider)?,
+            by_name: self.sni.iter()
+                .map(|(name, files)| Ok((name.clone(), files.load(&provider)?)))
+                .collect::<Result<_, TlsError>>()?,
+        };
+
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Config)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return Some(Arc::clone(&self.default));
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{parent}"));
+        let found = self.by_name.get(&name)
+            .or_else(|| wildcard.and_then(|w| self.by_name.get(&w)))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(found))
+    }
+}
+
+/// Loaded TLS config for [`crate::server::run_tls_server`], cheap to clone
+#[derive(Clone)]
+pub struct Tls {
+    settings: Arc<TlsSettings>,
+    config: RustlsConfig,
+}
+impl Tls {
+    /// Read the certificate files again. New handshakes use them right away, established
+    /// connections keep going with the old ones. On error the old certificates stay in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        self.config.reload_from_config(self.settings.server_config()?);
+        info!("Reloaded TLS certificates");
+        Ok(())
+    }
+
+    /// A reloader for [`runtime::run`] that calls [`Tls::reload`]
+    pub fn reloader(&self) -> BoxedReload {
+        let tls = self.clone();
+        reloader(move |_| std::future::ready(tls.reload()))
+    }
+
+    pub(crate) fn rustls_config(&self) -> RustlsConfig {
+        self.config.clone()
+    }
+}
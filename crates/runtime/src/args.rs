@@ -5,6 +5,36 @@ pub enum ArgError {
     InvalidBool(String),
     #[error("Flag -{} is missing an argument", .0)]
     MissingArg(String),
+    #[error("Unknown flag -{}", .0)]
+    UnknownFlag(String),
+    #[error("Invalid value {value:?} for -{flag}: {reason}")]
+    InvalidValue { flag: String, value: String, reason: String },
+    #[error("Missing required flag -{}", .0)]
+    MissingFlag(String),
+    #[error("Missing required argument <{}>", .0)]
+    MissingPositional(String),
+    #[error("Unexpected argument {:?}", .0)]
+    UnexpectedPositional(String),
+    #[error("Unknown command {:?}", .0)]
+    UnknownSubcommand(String),
+    #[error("Missing command")]
+    MissingSubcommand,
+    #[error("Failed to read response file {path}")]
+    ResponseFile { path: String, #[source] error: std::sync::Arc<std::io::Error> },
+    #[error("In command '{command}': {error}")]
+    InSubcommand { command: String, error: Box<ArgError> },
+}
+impl ArgError {
+    fn in_subcommand(self, name: &str) -> Self {
+        match self {
+            // Keep a single error with the full command path, like "db migrate"
+            ArgError::InSubcommand { command, error } => ArgError::InSubcommand {
+                command: format!("{name} {command}"),
+                error,
+            },
+            error => ArgError::InSubcommand { command: name.into(), error: Box::new(error) },
+        }
+    }
 }
 
 /// Parse a boolean flag; true is "-c" or "-c=true", false is "-c=false"
@@ -18,7 +48,7 @@ pub fn parse_flag_optional_bool(value: Option<&str>) -> Result<bool, ArgError> {
 }
 /// Parse a required parameter for an option, either inline or as the next arg
 pub fn parse_param(
-    flag: &str, args: &mut impl Iterator<Item = String>, inline: Option<&str>
+    flag: &str, args: &mut (impl Iterator<Item = String> + ?Sized), inline: Option<&str>
 ) -> Result<String, ArgError> {
     match inline {
         Some(v) => Ok(v.into()),
@@ -45,15 +75,15 @@ where
             let (flag, inline) = arg[1..].split_once('=').unzip();
             let flag = flag.unwrap_or(&arg[1..]);
 
-            if flag == "-" && inline == None {
+            if flag == "-" && inline.is_none() {
                 in_flags = false;
             } else {
                 let res = handle_flag(flag, inline, &mut args, &arg0)?;
-                if res == None { return Ok(None); }
+                if res.is_none() { return Ok(None); }
             }
         } else {
             let res = handle_pos(pos_index, arg)?;
-            if res == None { return Ok(None); }
+            if res.is_none() { return Ok(None); }
             pos_index += 1;
         }
     }
@@ -61,3 +91,534 @@ where
     Ok(Some(()))
 }
 
+
+
+fn validate_as<T>(value: &str) -> Result<(), String> where T: std::str::FromStr, T::Err: std::fmt::Display {
+    value.parse::<T>().map(drop).map_err(|e| e.to_string())
+}
+
+pub struct Flag {
+    name: &'static str,
+    short: Option<char>,
+    // None for boolean switches
+    value_name: Option<&'static str>,
+    help: &'static str,
+    default: Option<String>,
+    env: Option<&'static str>,
+    required: bool,
+    repeated: bool,
+    validate: fn(&str) -> Result<(), String>,
+}
+impl Flag {
+    /// A boolean flag, "-name", "-name=false" or "-no-name"
+    pub fn switch(name: &'static str) -> Self {
+        Flag {
+            name,
+            short: None,
+            value_name: None,
+            help: "",
+            default: None,
+            env: None,
+            required: false,
+            repeated: false,
+            validate: |v| parse_flag_optional_bool(Some(v)).map(drop).map_err(|e| e.to_string()),
+        }
+    }
+    /// A flag taking a value ("-name=value" or "-name value") which has to parse as `T`
+    pub fn value<T>(name: &'static str, value_name: &'static str) -> Self
+        where T: std::str::FromStr, T::Err: std::fmt::Display
+    {
+        Flag {
+            value_name: Some(value_name),
+            validate: validate_as::<T>,
+            ..Flag::switch(name)
+        }
+    }
+    pub fn help(self, help: &'static str) -> Self {
+        Flag { help, ..self }
+    }
+    pub fn default(self, default: impl Into<String>) -> Self {
+        Flag { default: Some(default.into()), ..self }
+    }
+    /// Environment variable used when the flag isn't given on the command line
+    pub fn env(self, env: &'static str) -> Self {
+        Flag { env: Some(env), ..self }
+    }
+    pub fn required(self) -> Self {
+        Flag { required: true, ..self }
+    }
+    /// Single-letter alias, e.g. "-v" for "-verbose"; see [`ArgSpec::grouped_short_flags`]
+    pub fn short(self, short: char) -> Self {
+        Flag { short: Some(short), ..self }
+    }
+    /// Collect every occurrence instead of keeping the last one, e.g. "-v -v -v"
+    pub fn repeated(self) -> Self {
+        Flag { repeated: true, ..self }
+    }
+}
+
+pub struct Positional {
+    name: &'static str,
+    help: &'static str,
+    required: bool,
+}
+impl Positional {
+    pub fn new(name: &'static str) -> Self {
+        Positional { name, help: "", required: false }
+    }
+    pub fn help(self, help: &'static str) -> Self {
+        Positional { help, ..self }
+    }
+    pub fn required(self) -> Self {
+        Positional { required: true, ..self }
+    }
+}
+
+/// Declarative flag definitions on top of [`parse_args`], with generated `-help` output
+#[derive(Default)]
+pub struct ArgSpec {
+    about: &'static str,
+    flags: Vec<Flag>,
+    positionals: Vec<Positional>,
+    subcommands: Vec<(&'static str, ArgSpec)>,
+    long_flags: bool,
+    grouped_short_flags: bool,
+    response_files: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Parsed {
+    values: std::collections::HashMap<&'static str, Vec<String>>,
+    positional_names: Vec<&'static str>,
+    positionals: Vec<String>,
+    subcommand: Option<(&'static str, Box<Parsed>)>,
+}
+
+impl ArgSpec {
+    pub fn new(about: &'static str) -> Self {
+        ArgSpec { about, ..Default::default() }
+    }
+    /// Panics if the flag's default isn't a valid value
+    pub fn flag(mut self, flag: Flag) -> Self {
+        if let Some(default) = &flag.default {
+            if let Err(reason) = (flag.validate)(default) {
+                panic!("Invalid default {default:?} for -{}: {reason}", flag.name);
+            }
+        }
+        self.flags.push(flag);
+        self
+    }
+    /// Positionals are filled in the order they're added; required ones must come first.
+    /// Panics if the spec has subcommands.
+    pub fn positional(mut self, positional: Positional) -> Self {
+        assert!(self.subcommands.is_empty(), "<{}>: specs with subcommands take no positionals", positional.name);
+        self.positionals.push(positional);
+        self
+    }
+    /// A subcommand with its own flags and positionals, selected by the first positional argument.
+    /// Flags given before it are parsed by this spec; specs with subcommands take no positionals.
+    /// It inherits [`ArgSpec::long_flags`], [`ArgSpec::grouped_short_flags`] and
+    /// [`ArgSpec::response_files`] from this spec. Panics if the spec has positionals.
+    pub fn subcommand(mut self, name: &'static str, spec: ArgSpec) -> Self {
+        assert!(self.positionals.is_empty(), "{name}: specs with subcommands take no positionals");
+        self.subcommands.push((name, spec));
+        self.inherit_options()
+    }
+    /// Also accept GNU-style "--name" for every flag
+    pub fn long_flags(self) -> Self {
+        ArgSpec { long_flags: true, ..self }.inherit_options()
+    }
+    /// Read "-abc" as "-a -b -c" when there's no flag named "abc"; only the last may take a value
+    pub fn grouped_short_flags(self) -> Self {
+        ArgSpec { grouped_short_flags: true, ..self }.inherit_options()
+    }
+    /// Replace "@path" arguments with the whitespace-separated contents of that file. On a
+    /// subcommand alone, only the arguments after its name are expanded.
+    pub fn response_files(self) -> Self {
+        ArgSpec { response_files: true, ..self }.inherit_options()
+    }
+
+    /// Pass the options down to subcommands, whichever order they were set in
+    fn inherit_options(mut self) -> Self {
+        let subcommands = std::mem::take(&mut self.subcommands);
+        self.subcommands = subcommands.into_iter()
+            .map(|(name, spec)| (name, ArgSpec {
+                long_flags: spec.long_flags || self.long_flags,
+                grouped_short_flags: spec.grouped_short_flags || self.grouped_short_flags,
+                response_files: spec.response_files || self.response_files,
+                ..spec
+            }.inherit_options()))
+            .collect();
+        self
+    }
+
+    pub fn help(&self, arg0: &str) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+
+        write!(out, "Usage: {arg0}").unwrap();
+        if !self.flags.is_empty() { write!(out, " [flags]").unwrap(); }
+        if !self.subcommands.is_empty() { write!(out, " <command> ...").unwrap(); }
+        for pos in &self.positionals {
+            if pos.required {
+                write!(out, " <{}>", pos.name).unwrap();
+            } else {
+                write!(out, " [{}]", pos.name).unwrap();
+            }
+        }
+        writeln!(out).unwrap();
+        if !self.about.is_empty() {
+            writeln!(out, "\n{}", self.about).unwrap();
+        }
+
+        let flag_names: Vec<_> = self.flags.iter()
+            .map(|f| {
+                let short = f.short.map(|c| format!("-{c}, ")).unwrap_or_default();
+                match f.value_name {
+                    Some(value) => format!("{short}-{}={}", f.name, value),
+                    None => format!("{short}-{}", f.name),
+                }
+            })
+            .chain(std::iter::once("-help".into()))
+            .collect();
+        let width = flag_names.iter().map(|n| n.len())
+            .chain(self.positionals.iter().map(|p| p.name.len() + 2))
+            .chain(self.subcommands.iter().map(|(name, _)| name.len()))
+            .max().unwrap_or(0);
+
+        if !self.subcommands.is_empty() {
+            writeln!(out, "\nCommands:").unwrap();
+            for (name, spec) in &self.subcommands {
+                let line = format!("  {:width$}  {}", name, spec.about);
+                writeln!(out, "{}", line.trim_end()).unwrap();
+            }
+        }
+
+        if !self.positionals.is_empty() {
+            writeln!(out, "\nArguments:").unwrap();
+            for pos in &self.positionals {
+                let line = format!("  {:width$}  {}", format!("<{}>", pos.name), pos.help);
+                writeln!(out, "{}", line.trim_end()).unwrap();
+            }
+        }
+
+        writeln!(out, "\nFlags:").unwrap();
+        for (flag, name) in self.flags.iter().zip(&flag_names) {
+            let mut extra = Vec::new();
+            if flag.required { extra.push("required".to_string()); }
+            if flag.repeated { extra.push("repeatable".to_string()); }
+            if let Some(default) = &flag.default { extra.push(format!("default: {default}")); }
+            if let Some(env) = flag.env { extra.push(format!("env: {env}")); }
+            let mut line = format!("  {:width$}  {}", name, flag.help);
+            if !extra.is_empty() {
+                if !flag.help.is_empty() { line.push(' '); }
+                write!(line, "({})", extra.join(", ")).unwrap();
+            }
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+        writeln!(out, "  {:width$}  Show this help", "-help").unwrap();
+        out
+    }
+
+    /// Parse the arguments (including arg0); returns `None` if help was requested and printed
+    pub fn parse(&self, args: impl Iterator<Item = String>) -> Result<Option<Parsed>, ArgError> {
+        if self.response_files {
+            self.parse_dyn(&mut expand_response_files(args)?.into_iter())
+        } else {
+            self.parse_dyn(&mut { args })
+        }
+    }
+
+    /// "--name" is "-name" with [`ArgSpec::long_flags`]; `flag` is without the first dash
+    fn long_name<'a>(&self, flag: &'a str) -> &'a str {
+        match flag.strip_prefix('-') {
+            Some(long) if self.long_flags => long,
+            _ => flag,
+        }
+    }
+
+    /// "-help", and "-h" unless a flag of ours has that name
+    fn is_help(&self, flag: &str) -> bool {
+        let flag = self.long_name(flag);
+        matches!(flag, "help" | "h") && self.find_flag(flag).is_none()
+    }
+
+    fn find_flag(&self, name: &str) -> Option<&Flag> {
+        let mut chars = name.chars();
+        let short = match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        };
+        self.flags.iter().find(|f| f.name == name || (short.is_some() && f.short == short))
+    }
+
+    fn set_flag(
+        &self,
+        values: &mut std::collections::HashMap<&'static str, Vec<String>>,
+        spec: &Flag,
+        value: String,
+    ) -> Result<(), ArgError> {
+        (spec.validate)(&value)
+            .map_err(|reason| ArgError::InvalidValue { flag: spec.name.into(), value: value.clone(), reason })?;
+        // Switches are stored as "true"/"false" whichever spelling they came in
+        let value = match spec.value_name {
+            None => parse_flag_optional_bool(Some(&value))?.to_string(),
+            Some(_) => value,
+        };
+        let entry = values.entry(spec.name).or_default();
+        if !spec.repeated { entry.clear(); }
+        entry.push(value);
+        Ok(())
+    }
+
+    fn handle_flag(
+        &self,
+        values: &mut std::collections::HashMap<&'static str, Vec<String>>,
+        flag: &str,
+        inline: Option<&str>,
+        args: &mut dyn Iterator<Item = String>,
+    ) -> Result<(), ArgError> {
+        let flag = self.long_name(flag);
+
+        if let Some(spec) = self.find_flag(flag) {
+            let value = match spec.value_name {
+                None => parse_flag_optional_bool(inline)?.to_string(),
+                Some(_) => parse_param(flag, args, inline)?,
+            };
+            return self.set_flag(values, spec, value);
+        }
+
+        // -no-name negates a switch
+        if let Some(spec) = flag.strip_prefix("no-").and_then(|f| self.find_flag(f)) {
+            if spec.value_name.is_none() {
+                if let Some(value) = inline {
+                    return Err(ArgError::InvalidValue {
+                        flag: flag.into(), value: value.into(), reason: "negated flags don't take a value".into(),
+                    });
+                }
+                return self.set_flag(values, spec, "false".into());
+            }
+        }
+
+        if self.grouped_short_flags && flag.chars().count() > 1 {
+            let group: Option<Vec<_>> = flag.chars()
+                .map(|c| self.flags.iter().find(|f| f.short == Some(c)))
+                .collect();
+            if let Some((last, rest)) = group.as_deref().and_then(|g| g.split_last()) {
+                if rest.iter().all(|f| f.value_name.is_none()) {
+                    for spec in rest {
+                        self.set_flag(values, spec, "true".into())?;
+                    }
+                    let value = match last.value_name {
+                        None => parse_flag_optional_bool(inline)?.to_string(),
+                        Some(_) => parse_param(flag, args, inline)?,
+                    };
+                    return self.set_flag(values, last, value);
+                }
+            }
+        }
+
+        Err(ArgError::UnknownFlag(flag.into()))
+    }
+
+    // Not generic, since subcommands would otherwise instantiate it recursively
+    fn parse_dyn(&self, args: &mut dyn Iterator<Item = String>) -> Result<Option<Parsed>, ArgError> {
+        let mut values = std::collections::HashMap::new();
+        let mut positionals = Vec::new();
+        let mut subcommand = None;
+
+        let arg0 = args.next().unwrap_or_else(|| "unknown".into());
+        let res = parse_args(std::iter::once(arg0.clone()).chain(&mut *args), |flag, inline, args, arg0| {
+            if self.is_help(flag) {
+                print!("{}", self.help(arg0));
+                return Ok(None);
+            }
+            self.handle_flag(&mut values, flag, inline, args)?;
+            Ok(Some(()))
+        }, |index, arg| {
+            if !self.subcommands.is_empty() {
+                let (name, spec) = self.subcommands.iter().find(|(name, _)| *name == arg)
+                    .ok_or(ArgError::UnknownSubcommand(arg))?;
+                // Stop here; the rest of the arguments belong to the subcommand
+                subcommand = Some((*name, spec));
+                return Ok(None);
+            }
+            if index >= self.positionals.len() {
+                return Err(ArgError::UnexpectedPositional(arg));
+            }
+            positionals.push(arg);
+            Ok(Some(()))
+        })?;
+        if res.is_none() && subcommand.is_none() {
+            return Ok(None);
+        }
+
+        // First, so "prog serve -help" works without the flags this spec requires
+        let subcommand = match subcommand {
+            Some((name, spec)) => {
+                let sub_args = std::iter::once(format!("{arg0} {name}")).chain(args);
+                // Expanded up front when this spec has them too
+                let mut sub_args: Box<dyn Iterator<Item = String>> = match spec.response_files && !self.response_files {
+                    true => Box::new(expand_response_files(sub_args).map_err(|e| e.in_subcommand(name))?.into_iter()),
+                    false => Box::new(sub_args),
+                };
+                match spec.parse_dyn(&mut sub_args).map_err(|e| e.in_subcommand(name))? {
+                    Some(parsed) => Some((name, Box::new(parsed))),
+                    None => return Ok(None),
+                }
+            },
+            None if !self.subcommands.is_empty() => return Err(ArgError::MissingSubcommand),
+            None => None,
+        };
+
+        for flag in &self.flags {
+            if values.contains_key(flag.name) {
+                continue;
+            }
+            if let Some(value) = flag.env.and_then(|env| std::env::var(env).ok()) {
+                self.set_flag(&mut values, flag, value)?;
+            } else if let Some(default) = &flag.default {
+                self.set_flag(&mut values, flag, default.clone())?;
+            } else if flag.required {
+                return Err(ArgError::MissingFlag(flag.name.into()));
+            }
+        }
+        if let Some(missing) = self.positionals.iter().filter(|p| p.required).nth(positionals.len()) {
+            return Err(ArgError::MissingPositional(missing.name.into()));
+        }
+
+        Ok(Some(Parsed {
+            values,
+            positional_names: self.positionals.iter().map(|p| p.name).collect(),
+            positionals,
+            subcommand,
+        }))
+    }
+}
+
+impl Parsed {
+    /// Value of a flag converted to `T`, after falling back to its env var and default
+    pub fn get<T>(&self, name: &str) -> Result<Option<T>, ArgError>
+        where T: std::str::FromStr, T::Err: std::fmt::Display
+    {
+        Ok(self.get_all(name)?.pop())
+    }
+    /// Every value given for a [`Flag::repeated`] flag, in order
+    pub fn get_all<T>(&self, name: &str) -> Result<Vec<T>, ArgError>
+        where T: std::str::FromStr, T::Err: std::fmt::Display
+    {
+        self.values.get(name).into_iter().flatten()
+            .map(|value| value.parse::<T>().map_err(|e| ArgError::InvalidValue {
+                flag: name.into(), value: value.clone(), reason: e.to_string(),
+            }))
+            .collect()
+    }
+    /// Whether a switch is set; false if it was never given
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.get(name).and_then(|v| v.last()).is_some_and(|v| v == "true")
+    }
+    /// How many times a repeated switch was set, e.g. 3 for "-v -v -v"
+    pub fn count(&self, name: &str) -> usize {
+        self.values.get(name).map_or(0, |v| v.iter().filter(|v| *v == "true").count())
+    }
+    pub fn positional(&self, name: &str) -> Option<&str> {
+        let index = self.positional_names.iter().position(|n| *n == name)?;
+        self.positionals.get(index).map(|s| &s[..])
+    }
+    pub fn positionals(&self) -> &[String] {
+        &self.positionals
+    }
+    /// The selected subcommand and its own parsed arguments
+    pub fn subcommand(&self) -> Option<(&'static str, &Parsed)> {
+        self.subcommand.as_ref().map(|(name, parsed)| (*name, &**parsed))
+    }
+}
+
+/// Expand "@path" arguments (after arg0) into the whitespace-separated words of the file
+pub fn expand_response_files(args: impl Iterator<Item = String>) -> Result<Vec<String>, ArgError> {
+    let mut out = Vec::new();
+    for (i, arg) in args.enumerate() {
+        match arg.strip_prefix('@') {
+            Some(path) if i > 0 && !path.is_empty() => {
+                let text = std::fs::read_to_string(path).map_err(|e| ArgError::ResponseFile {
+                    path: path.into(),
+                    error: std::sync::Arc::new(e),
+                })?;
+                out.extend(text.split_whitespace().map(String::from));
+            },
+            _ => out.push(arg),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &ArgSpec, args: &[&str]) -> Result<Option<Parsed>, ArgError> {
+        spec.parse(std::iter::once("prog").chain(args.iter().copied()).map(str::to_owned))
+    }
+
+    fn serve() -> ArgSpec {
+        ArgSpec::new("Serve")
+            .flag(Flag::switch("verbose").short('v'))
+            .flag(Flag::switch("quiet").short('q'))
+            .flag(Flag::value::<u16>("port", "PORT").short('p'))
+    }
+
+    #[test]
+    fn subcommands_inherit_options() {
+        // Set before and after adding the subcommand
+        for spec in [
+            ArgSpec::new("").long_flags().grouped_short_flags().subcommand("serve", serve()),
+            ArgSpec::new("").subcommand("serve", serve()).long_flags().grouped_short_flags(),
+        ] {
+            let parsed = parse(&spec, &["serve", "--verbose", "-qp", "80"]).unwrap().unwrap();
+            let (_, serve) = parsed.subcommand().unwrap();
+            assert!(serve.flag("verbose") && serve.flag("quiet"));
+            assert_eq!(serve.get::<u16>("port").unwrap(), Some(80));
+        }
+        assert!(parse(&ArgSpec::new("").subcommand("serve", serve()), &["serve", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn subcommand_help_before_parent_requirements() {
+        let spec = ArgSpec::new("").flag(Flag::value::<String>("config", "PATH").required()).subcommand("serve", serve());
+        assert!(parse(&spec, &["serve", "-help"]).unwrap().is_none());
+        assert!(parse(&spec, &["-config", "a", "serve", "-h"]).unwrap().is_none());
+        assert!(matches!(parse(&spec, &["serve"]), Err(ArgError::MissingFlag(flag)) if flag == "config"));
+        let parsed = parse(&spec, &["-config", "a", "serve"]).unwrap().unwrap();
+        assert_eq!(parsed.get::<String>("config").unwrap().as_deref(), Some("a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "specs with subcommands take no positionals")]
+    fn subcommands_exclude_positionals() {
+        let _ = ArgSpec::new("").positional(Positional::new("file")).subcommand("serve", serve());
+    }
+
+    #[test]
+    #[should_panic(expected = "specs with subcommands take no positionals")]
+    fn positionals_exclude_subcommands() {
+        let _ = ArgSpec::new("").subcommand("serve", serve()).positional(Positional::new("file"));
+    }
+
+    #[test]
+    fn subcommand_response_files() {
+        let path = std::env::temp_dir().join(format!("runtime-args-test-{}", std::process::id()));
+        std::fs::write(&path, "-verbose -port 80").unwrap();
+        let arg = format!("@{}", path.display());
+
+        for spec in [
+            ArgSpec::new("").response_files().subcommand("serve", serve()),
+            ArgSpec::new("").subcommand("serve", serve().response_files()),
+        ] {
+            let parsed = parse(&spec, &["serve", &arg]).unwrap().unwrap();
+            let (_, serve) = parsed.subcommand().unwrap();
+            assert!(serve.flag("verbose"));
+            assert_eq!(serve.get::<u16>("port").unwrap(), Some(80));
+        }
+        std::fs::remove_file(&path).ok();
+    }
+}
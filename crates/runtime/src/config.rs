@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use serde::de::DeserializeOwned;
+
+use crate::reload::{reloader, BoxedReload};
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Config file {} has an unknown extension (expected .toml, .yaml or .yml)", .0.display())]
+    UnknownFormat(PathBuf),
+    #[error("Failed to parse config file {}", .0.display())]
+    Toml(PathBuf, #[source] toml::de::Error),
+    #[error("Failed to parse config file {}", .0.display())]
+    Yaml(PathBuf, #[source] serde_yaml::Error),
+    #[error("Invalid config in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+type ValidateFn<T> = dyn Fn(&T) -> Result<(), String> + Send + Sync;
+type Validator<T> = Box<ValidateFn<T>>;
+
+struct ConfigInner<T> {
+    path: PathBuf,
+    format: ConfigFormat,
+    validate: Option<Validator<T>>,
+    current: ArcSwap<T>,
+}
+
+/// A config file parsed into `T`, which can be re-read while the program is running.
+/// Clones share the same underlying config.
+pub struct Config<T>(Arc<ConfigInner<T>>);
+impl<T> Clone for Config<T> {
+    fn clone(&self) -> Self {
+        Config(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Config<T> where T: DeserializeOwned + Send + Sync + 'static {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        Self::load_inner(path.into(), None)
+    }
+
+    /// Like [`Config::load`], but every loaded config also has to pass `validate`
+    pub fn load_validated<F>(path: impl Into<PathBuf>, validate: F) -> Result<Self, ConfigError>
+        where F: Fn(&T) -> Result<(), String> + Send + Sync + 'static
+    {
+        Self::load_inner(path.into(), Some(Box::new(validate)))
+    }
+
+    fn load_inner(path: PathBuf, validate: Option<Validator<T>>) -> Result<Self, ConfigError> {
+        let format = ConfigFormat::from_path(&path)
+            .ok_or_else(|| ConfigError::UnknownFormat(path.clone()))?;
+        let initial = read_config(&path, format, validate.as_deref())?;
+        Ok(Config(Arc::new(ConfigInner {
+            path,
+            format,
+            validate,
+            current: ArcSwap::from_pointee(initial),
+        })))
+    }
+
+    /// Snapshot of the current config; it stays unchanged even if a reload happens meanwhile
+    pub fn get(&self) -> Arc<T> {
+        self.0.current.load_full()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+
+    /// Re-read the file, keeping the old config if the new one fails to parse or validate
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = read_config(&self.0.path, self.0.format, self.0.validate.as_deref())?;
+        self.0.current.store(Arc::new(config));
+        info!("Reloaded config from {}", self.0.path.display());
+        Ok(())
+    }
+
+    /// Reload callback for [`crate::run`]; a failed reload is logged and the old config kept
+    pub fn reloader(&self) -> BoxedReload {
+        let config = self.clone();
+        reloader(move |_| std::future::ready(config.reload()))
+    }
+}
+
+fn read_config<T>(path: &Path, format: ConfigFormat, validate: Option<&ValidateFn<T>>) -> Result<T, ConfigError>
+    where T: DeserializeOwned
+{
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::Read(path.into(), e))?;
+    let config: T = match format {
+        ConfigFormat::Toml => toml::from_str(&text).map_err(|e| ConfigError::Toml(path.into(), e))?,
+        ConfigFormat::Yaml => serde_yaml::from_str(&text).map_err(|e| ConfigError::Yaml(path.into(), e))?,
+    };
+    if let Some(validate) = validate {
+        validate(&config).map_err(|e| ConfigError::Invalid(path.into(), e))?;
+    }
+    Ok(config)
+}
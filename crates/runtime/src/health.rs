@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Not started yet, or waiting out its backoff before a restart
+    Starting,
+    Running,
+    /// Cancelled by shutdown but hasn't exited yet
+    Stopping,
+    Exited,
+}
+impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Starting => "starting",
+            TaskState::Running => "running",
+            TaskState::Stopping => "stopping",
+            TaskState::Exited => "exited",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskHealth {
+    pub name: &'static str,
+    pub state: TaskState,
+    pub restarts: u32,
+    /// Most recent failure, kept across restarts
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthSnapshot {
+    pub shutting_down: bool,
+    pub tasks: Vec<TaskHealth>,
+}
+impl HealthSnapshot {
+    /// Ready to take traffic: not shutting down and every task is running
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down && self.tasks.iter().all(|t| t.state == TaskState::Running)
+    }
+}
+
+#[derive(Debug)]
+struct HealthInner {
+    shutting_down: bool,
+    tasks: Vec<TaskHealth>,
+    unready_delay: Duration,
+}
+impl Default for HealthInner {
+    fn default() -> Self {
+        HealthInner {
+            shutting_down: false,
+            tasks: Vec::new(),
+            unready_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// State of the tasks run by [`crate::run`], shared through [`crate::RunHandle::health`]
+#[derive(Debug, Clone, Default)]
+pub struct HealthRegistry(Arc<Mutex<HealthInner>>);
+impl HealthRegistry {
+    pub fn snapshot(&self) -> HealthSnapshot {
+        let inner = self.0.lock().unwrap();
+        HealthSnapshot {
+            shutting_down: inner.shutting_down,
+            tasks: inner.tasks.clone(),
+        }
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.0.lock().unwrap().shutting_down
+    }
+
+    /// How long shutdown reports not-ready before any task is cancelled, so load
+    /// balancers have time to stop routing requests here. 5 seconds by default; a second
+    /// SIGINT or SIGTERM cuts it short, zero turns it off.
+    pub fn set_unready_delay(&self, delay: Duration) {
+        self.0.lock().unwrap().unready_delay = delay;
+    }
+    pub(crate) fn unready_delay(&self) -> Duration {
+        self.0.lock().unwrap().unready_delay
+    }
+
+    pub(crate) fn init(&self, names: impl Iterator<Item = &'static str>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.shutting_down = false;
+        inner.tasks = names
+            .map(|name| TaskHealth { name, state: TaskState::Starting, restarts: 0, last_error: None })
+            .collect();
+    }
+    pub(crate) fn begin_shutdown(&self) {
+        self.0.lock().unwrap().shutting_down = true;
+    }
+    pub(crate) fn set_state(&self, index: usize, state: TaskState) {
+        if let Some(task) = self.0.lock().unwrap().tasks.get_mut(index) {
+            task.state = state;
+        }
+    }
+    /// `state` is [`TaskState::Starting`] for tasks that report readiness themselves
+    pub(crate) fn set_started(&self, index: usize, restarts: u32, state: TaskState) {
+        if let Some(task) = self.0.lock().unwrap().tasks.get_mut(index) {
+            task.state = state;
+            task.restarts = restarts;
+        }
+    }
+    pub(crate) fn set_exited(&self, index: usize, error: Option<String>) {
+        if let Some(task) = self.0.lock().unwrap().tasks.get_mut(index) {
+            task.state = TaskState::Exited;
+            if error.is_some() {
+                task.last_error = error;
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 
-#[allow(unused)]
+#[allow(unused_imports)]
 #[macro_use]
 extern crate tracing;
 
@@ -10,105 +10,375 @@ pub mod args;
 pub mod utils;
 pub mod log;
 pub mod template;
+pub mod task;
+pub mod reload;
+pub mod config;
+pub mod health;
+pub mod systemd;
+pub mod upgrade;
+pub mod metrics;
 
 
-type BoxedTask = Box<dyn FnOnce(CancellationToken) -> Box<dyn std::future::Future<Output = ()> + Send + 'static>>;
-
-pub fn handler<Func, Fut>(f: Func) -> BoxedTask
-where
-    Func: FnOnce(CancellationToken) -> Fut + 'static,
-    Fut: std::future::Future<Output = ()> + Send + 'static
-{
-    Box::new(move |c| Box::new(f(c)))
-}
+pub use task::{handler, handler_once, BoxedTask, IntoTaskResult, RestartMode, RestartPolicy, TaskError, TaskResult};
+pub use reload::{reloader, reload_fn, no_reload, BoxedReload, ReloadEvent, ReloadTrigger};
+pub use health::{HealthRegistry, HealthSnapshot, TaskState};
 
 struct RunHandleInner {
-    reload_channel: (flume::Sender<()>, flume::Receiver<()>),
+    reload_channel: (flume::Sender<Option<String>>, flume::Receiver<Option<String>>),
     shutdown_channel: (flume::Sender<()>, flume::Receiver<()>),
+    upgrade_channel: (flume::Sender<()>, flume::Receiver<()>),
+    reload_events: tokio::sync::watch::Sender<ReloadEvent>,
+    health: HealthRegistry,
 }
 
 #[derive(Clone)]
 pub struct RunHandle(std::sync::Arc<RunHandleInner>);
+impl Default for RunHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl RunHandle {
     pub fn new() -> Self {
         RunHandle(std::sync::Arc::new(RunHandleInner {
             reload_channel: flume::unbounded(),
             shutdown_channel: flume::unbounded(),
+            upgrade_channel: flume::unbounded(),
+            reload_events: tokio::sync::watch::Sender::new(ReloadEvent {
+                generation: 0,
+                trigger: ReloadTrigger::Startup,
+            }),
+            health: HealthRegistry::default(),
         }))
     }
     pub fn signal_reload(&self) {
-        self.0.reload_channel.0.send(()).ok();
+        self.0.reload_channel.0.send(None).ok();
+    }
+    pub fn signal_reload_with(&self, reason: impl Into<String>) {
+        self.0.reload_channel.0.send(Some(reason.into())).ok();
+    }
+    /// Watch successful reloads; the receiver always holds the latest generation
+    pub fn subscribe_reload(&self) -> tokio::sync::watch::Receiver<ReloadEvent> {
+        self.0.reload_events.subscribe()
     }
     pub fn signal_shutdown(&self) {
         self.0.shutdown_channel.0.send(()).ok();
     }
+    /// Hand the listening sockets to a new copy of this executable, then shut down (see [`upgrade`])
+    pub fn signal_upgrade(&self) {
+        self.0.upgrade_channel.0.send(()).ok();
+    }
+    /// Task states and shutdown status, for health checks
+    pub fn health(&self) -> &HealthRegistry {
+        &self.0.health
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RunError {
+    #[error("Failed to install signal handler")]
+    Signal(#[from] std::io::Error),
+    #[error("Task {task} depends on unknown task {dependency}")]
+    UnknownDependency { task: &'static str, dependency: &'static str },
+    #[error("Task {} is part of a dependency cycle", .0)]
+    DependencyCycle(&'static str),
 }
 
-fn log_task_exit(result: Option<Result<&'_ str, tokio::task::JoinError>>) {
-    match result {
-        Some(Ok(ident)) => info!("task {} exited", ident),
-        Some(Err(e)) => warn!("task exited with failure: {}", e),
-        None => warn!("remaining tasks list is empty?"),
+struct TaskSlot {
+    ident: &'static str,
+    task: BoxedTask,
+    cancel: CancellationToken,
+    phase: usize,
+    restarts: u32,
+    started: std::time::Instant,
+}
+
+type TaskSet = tokio::task::JoinSet<TaskResult>;
+type TaskIds = std::collections::HashMap<tokio::task::Id, usize>;
+
+fn spawn_task(join_set: &mut TaskSet, ids: &mut TaskIds, health: &HealthRegistry, ready_tx: &flume::Sender<usize>, index: usize, slot: &mut TaskSlot) -> bool {
+    let Some(future) = slot.task.start(slot.cancel.child_token()) else {
+        warn!("task {} cannot be restarted", slot.ident);
+        return false;
+    };
+    let future = task::ReadySignal { index, tx: ready_tx.clone() }.scope(future);
+    let span = tracing::info_span!("task", name=slot.ident).or_current();
+    let abort = join_set.spawn(log::instrument(span, future));
+    ids.insert(abort.id(), index);
+    slot.started = std::time::Instant::now();
+    let state = if slot.task.signals_ready { TaskState::Starting } else { TaskState::Running };
+    health.set_started(index, slot.restarts, state);
+    let metrics = metrics::task_metrics();
+    metrics.up.with(&[slot.ident]).set(1);
+    // Created on first start so it shows up as 0; only restarts spawn with a nonzero count
+    let restarts = metrics.restarts.with(&[slot.ident]);
+    if slot.restarts > 0 {
+        restarts.inc();
     }
+    true
 }
 
+/// Logs the exit and returns the index of the exited task and whether it failed
+fn log_task_exit(slots: &[TaskSlot], ids: &mut TaskIds, health: &HealthRegistry, result: Option<Result<(tokio::task::Id, TaskResult), tokio::task::JoinError>>) -> Option<(usize, bool)> {
+    let (id, failed) = match &result {
+        Some(Ok((id, result))) => (*id, result.is_err()),
+        Some(Err(e)) => (e.id(), true),
+        None => {
+            warn!("remaining tasks list is empty?");
+            return None;
+        },
+    };
+    let index = ids.remove(&id)?;
+    let ident = slots[index].ident;
+    let error = match result {
+        Some(Ok((_, Ok(())))) => {
+            info!("task {} exited", ident);
+            None
+        },
+        Some(Ok((_, Err(e)))) => {
+            warn!("task {} exited with error: {}", ident, utils::format_error_disp(&*e));
+            Some(utils::format_error_disp(&*e).to_string())
+        },
+        Some(Err(e)) => {
+            warn!("task {} exited with failure: {}", ident, e);
+            Some(e.to_string())
+        },
+        None => unreachable!(),
+    };
+    health.set_exited(index, error);
+    metrics::task_metrics().up.with(&[ident]).set(0);
+    Some((index, failed))
+}
+
+/// Decides whether an exited task gets restarted, returning the backoff delay if so
+fn plan_restart(slot: &mut TaskSlot, failed: bool) -> Option<std::time::Duration> {
+    let policy = &slot.task.restart;
+    if !policy.wants_restart(failed) {
+        return None;
+    }
+    if slot.started.elapsed() >= policy.reset_after {
+        slot.restarts = 0;
+    }
+    if policy.max_restarts.is_some_and(|max| slot.restarts >= max) {
+        warn!("task {} used up its restart budget ({} restarts)", slot.ident, slot.restarts);
+        return None;
+    }
+    let delay = policy.backoff_for(slot.restarts);
+    slot.restarts += 1;
+    Some(delay)
+}
+
+/// Once every task is serving; a process started by an upgrade takes over only then
+fn notify_started() {
+    systemd::notify_ready();
+    upgrade::notify_parent_ready();
+}
+
+type Upgrading = std::pin::Pin<Box<dyn std::future::Future<Output = Result<u32, upgrade::UpgradeError>>>>;
+type Reloading = std::pin::Pin<Box<dyn std::future::Future<Output = (BoxedReload, ReloadEvent, bool)>>>;
+
+/// Takes the reloader for as long as the reload runs, the supervisor gets it back when it's done
+fn start_reload(handle: &RunHandle, mut reload: BoxedReload, trigger: ReloadTrigger) -> Reloading {
+    let event = ReloadEvent {
+        generation: handle.0.reload_events.borrow().generation + 1,
+        trigger,
+    };
+    let span = tracing::info_span!("reload", generation=event.generation).or_current();
+    systemd::notify_reloading();
+    // For logrotate, which moves the file away before sending SIGHUP
+    log::file::reopen();
+    Box::pin(log::instrument(span, async move {
+        let ok = reload.run(&event).await;
+        (reload, event, ok)
+    }))
+}
+
+const UPGRADE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 #[tracing::instrument(skip_all)]
 pub async fn run(
     handle: RunHandle,
     tasks: Vec<(&'static str, BoxedTask)>,
-    mut reload: Box<dyn FnMut()>,
-) -> Result<(), std::io::Error> {
-    let mut join_set = tokio::task::JoinSet::new();
-    let mut remaining_tasks = tasks.len();
-    let cancel = CancellationToken::new();
-
-    for (ident, task) in tasks {
-        let cancel_child = cancel.child_token();
-        let future = task(cancel_child);
-        let span = tracing::info_span!("task", name=ident).or_current();
-        join_set.spawn(async move {
-            log::instrument(span, Box::into_pin(future)).await;
-            ident
-        });
+    reload: BoxedReload,
+) -> Result<(), RunError> {
+    let mut join_set = TaskSet::new();
+    let mut task_ids = TaskIds::new();
+    let health = &handle.0.health;
+    // Tasks waiting out their backoff before being restarted
+    let mut pending_restarts = tokio::task::JoinSet::new();
+    // Indexes of tasks calling task::notify_ready
+    let (ready_tx, ready_rx) = flume::unbounded();
+
+    let dependencies: Vec<_> = tasks.iter().map(|(ident, task)| (*ident, &task.depends_on[..])).collect();
+    let phases = task::shutdown_phases(&dependencies)?;
+
+    let mut slots: Vec<_> = tasks.into_iter().zip(phases)
+        .map(|((ident, task), phase)| TaskSlot {
+            ident,
+            task,
+            cancel: CancellationToken::new(),
+            phase,
+            restarts: 0,
+            started: std::time::Instant::now(),
+        })
+        .collect();
+    health.init(slots.iter().map(|s| s.ident));
+
+    // Start dependencies before the tasks that use them
+    let mut start_order: Vec<usize> = (0..slots.len()).collect();
+    start_order.sort_by_key(|&i| std::cmp::Reverse(slots[i].phase));
+    for index in start_order {
+        spawn_task(&mut join_set, &mut task_ids, health, &ready_tx, index, &mut slots[index]);
     }
 
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
 
     let reload_rx = &handle.0.reload_channel.1;
     let shutdown_rx = &handle.0.shutdown_channel.1;
+    let upgrade_rx = &handle.0.upgrade_channel.1;
+
+    let mut watchdog = systemd::watchdog_interval().map(tokio::time::interval);
+    // The reloader while no reload is running
+    let mut reload = Some(reload);
+    let mut reloading: Option<Reloading> = None;
+    // Reloads requested during a reload are merged into one that runs after it
+    let mut queued_reload = None;
+    let mut upgrading: Option<Upgrading> = None;
+    // Tasks that still have to report they're serving before the process counts as ready
+    let mut awaiting_ready: std::collections::HashSet<usize> = (0..slots.len())
+        .filter(|&i| slots[i].task.signals_ready)
+        .collect();
+    if awaiting_ready.is_empty() {
+        notify_started();
+    }
 
     enum Action {
         Exit(&'static str),
-        Reload(&'static str),
+        Reload(&'static str, ReloadTrigger),
+        Reloaded(BoxedReload, ReloadEvent, bool),
+        Upgrade(&'static str),
+        Upgraded(Result<u32, upgrade::UpgradeError>),
+        Restart(usize),
+        Ready(usize),
+        TimedOut,
         Continue,
     }
 
     loop {
+        if join_set.is_empty() && pending_restarts.is_empty() {
+            warn!("No tasks left, starting shutdown");
+            break;
+        }
+
         let action = tokio::select! {
             // Signal handlers
-            _ = sighup.recv()  => Action::Reload("Received SIGHUP"),
+            _ = sighup.recv()  => Action::Reload("Received SIGHUP", ReloadTrigger::Sighup),
             _ = sigint.recv()  => Action::Exit("Received SIGINT"),
             _ = sigterm.recv() => Action::Exit("Received SIGTERM"),
+            _ = sigusr2.recv() => Action::Upgrade("Received SIGUSR2"),
 
             // Requests through RunHandle
-            _ = reload_rx.recv_async() => Action::Reload("Received reload request"),
+            Ok(reason) = reload_rx.recv_async() => Action::Reload("Received reload request", ReloadTrigger::Request(reason)),
             _ = shutdown_rx.recv_async() => Action::Exit("Received shutdown request"),
+            _ = upgrade_rx.recv_async() => Action::Upgrade("Received upgrade request"),
+
+            // join_next is cancel-safe. It returns None right away when every task is waiting
+            // out its backoff, which isn't an exit
+            result = join_set.join_next_with_id(), if !join_set.is_empty() => {
+                match log_task_exit(&slots, &mut task_ids, health, result) {
+                    Some((index, failed)) => match plan_restart(&mut slots[index], failed) {
+                        Some(delay) => {
+                            info!("restarting task {} in {:.1}s", slots[index].ident, delay.as_secs_f32());
+                            health.set_state(index, TaskState::Starting);
+                            pending_restarts.spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                index
+                            });
+                            Action::Continue
+                        },
+                        None => Action::Exit("Task exited"),
+                    },
+                    None => Action::Exit("Task exited"),
+                }
+            },
+            Some(Ok(index)) = pending_restarts.join_next() => Action::Restart(index),
+            Ok(index) = ready_rx.recv_async() => Action::Ready(index),
+            Some((reload, event, ok)) = async { Some(reloading.as_mut()?.await) }, if reloading.is_some() => {
+                Action::Reloaded(reload, event, ok)
+            },
+            Some(result) = async { Some(upgrading.as_mut()?.await) }, if upgrading.is_some() => Action::Upgraded(result),
 
-            // join_next is cancel-safe
-            result = join_set.join_next() => {
-                log_task_exit(result);
-                remaining_tasks -= 1;
-                Action::Exit("Task exited")
+            _ = async { watchdog.as_mut()?.tick().await; Some(()) }, if watchdog.is_some() => {
+                systemd::notify_watchdog();
+                Action::Continue
             },
         };
 
         match action {
-            Action::Continue => (),
-            Action::Reload(msg) => {
-                info!("{msg}, reloading config");
-                reload();
+            Action::Continue | Action::TimedOut => (),
+            Action::Ready(index) => {
+                // It may have exited since
+                if task_ids.values().any(|&i| i == index) {
+                    health.set_state(index, TaskState::Running);
+                }
+                if awaiting_ready.remove(&index) && awaiting_ready.is_empty() {
+                    info!("All tasks are ready");
+                    notify_started();
+                }
+            },
+            Action::Restart(index) => {
+                if !spawn_task(&mut join_set, &mut task_ids, health, &ready_tx, index, &mut slots[index]) {
+                    warn!("Task could not be restarted, starting shutdown");
+                    break;
+                }
+            },
+            Action::Reload(msg, trigger) => match reload.take() {
+                Some(idle) => {
+                    info!("{msg}, reloading config");
+                    reloading = Some(start_reload(&handle, idle, trigger));
+                },
+                None => {
+                    info!("{msg} during a reload, reloading again once it's done");
+                    queued_reload = Some(trigger);
+                },
+            },
+            Action::Reloaded(idle, event, ok) => {
+                reloading = None;
+                if ok {
+                    handle.0.reload_events.send_replace(event);
+                }
+                match queued_reload.take() {
+                    Some(trigger) => {
+                        info!("Running the queued reload");
+                        reloading = Some(start_reload(&handle, idle, trigger));
+                    },
+                    None => {
+                        reload = Some(idle);
+                        if awaiting_ready.is_empty() {
+                            systemd::notify_ready();
+                        }
+                    },
+                }
+            },
+            Action::Upgrade(msg) if upgrading.is_some() => info!("{msg}, but an upgrade is already running"),
+            Action::Upgrade(msg) => {
+                info!("{msg}, starting new process");
+                let span = tracing::info_span!("upgrade").or_current();
+                upgrading = Some(Box::pin(log::instrument(span, upgrade::spawn_upgrade(UPGRADE_TIMEOUT))));
+            },
+            Action::Upgraded(result) => {
+                upgrading = None;
+                match result {
+                    Ok(pid) => {
+                        info!("New process {pid} is ready, handing over");
+                        systemd::notify_mainpid(pid);
+                        break;
+                    },
+                    Err(e) => error!("Upgrade failed, continuing with this process: {}", utils::format_error_disp(&e)),
+                }
             },
             Action::Exit(msg) => {
                 warn!("{msg}, starting shutdown");
@@ -117,35 +387,86 @@ pub async fn run(
         }
     }
 
+    // Tasks still in backoff are simply never started again
+    pending_restarts.abort_all();
+    // A reload or upgrade still running is abandoned
+    drop(reloading);
+    drop(upgrading);
+
     log::instrument(tracing::info_span!("shut down").or_current(), async {
         info!("Starting to shut down");
-        cancel.cancel();
-
-        while remaining_tasks > 0 {
-            let action = tokio::select! {
-                _ = sigint.recv()  => Action::Exit("Received second SIGINT"),
-                _ = sigterm.recv() => Action::Exit("Received second SIGTERM"),
-
-                // join_next is cancel-safe
-                result = join_set.join_next() => {
-                    log_task_exit(result);
-                    remaining_tasks -= 1;
-                    Action::Continue
-                },
-            };
-            match action {
-                Action::Reload(_) => (),
-                Action::Continue => (),
-                Action::Exit(msg) => {
-                    warn!("{msg}, exiting immediately");
-                    break;
-                },
+        health.begin_shutdown();
+        systemd::notify_stopping();
+
+        let unready_delay = health.unready_delay();
+        // Nothing left to serve from, so nothing to wait for
+        if !unready_delay.is_zero() && !task_ids.is_empty() {
+            info!("Reporting not ready for {:.1}s before stopping tasks", unready_delay.as_secs_f32());
+            tokio::select! {
+                _ = tokio::time::sleep(unready_delay) => (),
+                _ = sigint.recv()  => warn!("Received second SIGINT, stopping tasks now"),
+                _ = sigterm.recv() => warn!("Received second SIGTERM, stopping tasks now"),
+            }
+        }
+
+        // Stop tasks in reverse dependency order, one phase at a time
+        let last_phase = slots.iter().map(|s| s.phase).max().unwrap_or(0);
+        'phases: for phase in 0..=last_phase {
+            let in_phase = |ids: &TaskIds| ids.values().any(|&i| slots[i].phase == phase);
+            if !in_phase(&task_ids) {
+                continue;
+            }
+
+            let timeout = slots.iter()
+                .filter(|s| s.phase == phase)
+                .map(|s| s.task.shutdown_timeout)
+                .max().unwrap_or_default();
+            info!("Stopping phase {phase} with {:.1}s timeout", timeout.as_secs_f32());
+            for (index, slot) in slots.iter().enumerate().filter(|(_, s)| s.phase == phase) {
+                slot.cancel.cancel();
+                if task_ids.values().any(|&i| i == index) {
+                    health.set_state(index, TaskState::Stopping);
+                }
+            }
+
+            let deadline = tokio::time::sleep(timeout);
+            tokio::pin!(deadline);
+            while in_phase(&task_ids) {
+                let action = tokio::select! {
+                    _ = sigint.recv()  => Action::Exit("Received second SIGINT"),
+                    _ = sigterm.recv() => Action::Exit("Received second SIGTERM"),
+                    _ = &mut deadline => Action::TimedOut,
+
+                    // join_next is cancel-safe
+                    result = join_set.join_next_with_id() => {
+                        log_task_exit(&slots, &mut task_ids, health, result);
+                        Action::Continue
+                    },
+                };
+                match action {
+                    Action::Reload(..) | Action::Reloaded(..) | Action::Upgrade(_) | Action::Upgraded(_) | Action::Restart(_) | Action::Ready(_) => (),
+                    Action::Continue => (),
+                    Action::TimedOut => {
+                        let stuck: Vec<_> = task_ids.values()
+                            .filter(|&&i| slots[i].phase == phase)
+                            .map(|&i| slots[i].ident)
+                            .collect();
+                        warn!("Shutdown phase {phase} timed out, abandoning tasks {stuck:?}");
+                        break;
+                    },
+                    Action::Exit(msg) => {
+                        warn!("{msg}, exiting immediately");
+                        break 'phases;
+                    },
+                }
             }
         }
 
         info!("Exiting");
     }).await;
 
+    log::file::flush(std::time::Duration::from_secs(1));
+    log::otlp::flush(std::time::Duration::from_secs(2));
     Ok(())
 }
 
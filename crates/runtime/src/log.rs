@@ -3,7 +3,11 @@ use tracing_subscriber::filter::Targets;
 use tracing_subscriber::registry::Registry;
 use tracing_subscriber::layer::{Layer, SubscriberExt};
 
-use crate::utils::parse::{take_char, take_while};
+pub mod file;
+pub mod otlp;
+mod ansi;
+
+pub use ansi::{ansi_to_html, ansi_to_html_with, HtmlStyle};
 
 
 #[inline]
@@ -32,145 +36,371 @@ macro_rules! instrument {
 }
 
 
-/// Safe in any html destination besides unquoted attributes (why do those exist...)
-fn write_html_escaped(w: &mut impl std::fmt::Write, text: &str) -> std::fmt::Result {
-    // Simplified version of ammonia's clean_text
-    for c in text.chars() {
-        let replacement = match c {
-            // this character, when confronted, will start a tag
-            '<' => "&lt;",
-            // in an unquoted attribute, will end the attribute value
-            '>' => "&gt;",
-            // in an attribute surrounded by double quotes, this character will end the attribute value
-            '\"' => "&quot;",
-            // in an attribute surrounded by single quotes, this character will end the attribute value
-            '\'' => "&apos;",
-            // starts an entity reference
-            '&' => "&amp;",
-            // a spec-compliant browser will perform this replacement anyway, but the middleware might not
-            '\0' => "&#65533;",
-            // ALL OTHER CHARACTERS ARE PASSED THROUGH VERBATIM
-            c => {
-                w.write_char(c)?;
-                continue;
-            }
-        };
-        w.write_str(replacement)?;
-    }
-    Ok(())
-}
-
-pub fn ansi_to_html(msg: &str) -> String {
-    use std::fmt::Write;
-    let mut out = String::with_capacity(msg.len());
-    let mut msg = &msg[..];
-    let mut count = 0;
-    while let Some(i) = msg.find("\x1b[") {
-        out.push_str(&msg[..i]);
-
-        let mut new_msg = &msg[i + 2 ..];
-        let (params, interm, end);
-
-        (params, new_msg) = take_while(new_msg, |c| matches!(c, '\x30'..='\x3F'));
-        (interm, new_msg) = take_while(new_msg, |c| matches!(c, '\x20'..='\x2F'));
-        (end, new_msg) = take_char(new_msg);
-
-        if let Some(c @ '\x40'..='\x7E') = end {
-            msg = new_msg;
-            match (params, interm, c) {
-                ("0", "", 'm') => {
-                    for _ in 0..count { out.push_str("</span>"); }
-                    count = 0;
-                }
-                _ => {
-                    write!(out, "<span class='ansi-").ok();
-                    write_html_escaped(&mut out, params).ok();
-                    write_html_escaped(&mut out, interm).ok();
-                    write_html_escaped(&mut out, c.encode_utf8(&mut [0; 4])).ok();
-                    write!(out, "'>").ok();
-                    count += 1;
-                }
-            }
-        } else {
-            out.push_str(&msg[i .. i + 2]);
-            msg = &msg[i + 2 ..]
-        }
-    }
-    out.push_str(&msg);
-    for _ in 0..count {
-        out.push_str("</span>");
-    }
-    out
+/// One formatted log event, as sent to [`LOG_LISTENER`]
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub level: tracing::Level,
+    pub target: std::sync::Arc<str>,
+    /// The line as shown in the terminal, with colors turned into spans (see [`ansi_to_html`])
+    pub html: std::sync::Arc<str>,
 }
 
+pub type LogReceiver = tokio::sync::broadcast::Receiver<std::sync::Arc<LogLine>>;
+
+/// Collects one event written by the fmt layer and publishes it as a [`LogLine`] when dropped
 pub struct AnsiHtmlWriter {
     buffer: Vec<u8>,
-    broadcast: tokio::sync::broadcast::Sender<std::sync::Arc<str>>,
+    level: tracing::Level,
+    target: std::sync::Arc<str>,
+}
+impl std::io::Write for AnsiHtmlWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
-impl AnsiHtmlWriter {
-    pub fn from_channel(tx: tokio::sync::broadcast::Sender<std::sync::Arc<str>>) -> Self {
-        Self {
-            buffer: Vec::new(),
-            broadcast: tx,
+impl Drop for AnsiHtmlWriter {
+    fn drop(&mut self) {
+        let msg = String::from_utf8_lossy(&self.buffer);
+        let msg = msg.trim_end_matches('\n');
+        if msg.is_empty() {
+            return;
         }
+        publish(LogLine {
+            level: self.level,
+            target: std::sync::Arc::clone(&self.target),
+            html: ansi_to_html(msg).into(),
+        });
     }
 }
-impl std::io::Write for AnsiHtmlWriter {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        if let Some(newline) = buf.iter().position(|c| *c == b'\n') {
-            self.buffer.extend(&buf[..newline]);
-            let msg = std::str::from_utf8(&self.buffer)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
-            self.broadcast.send(ansi_to_html(msg).into()).ok();
-            self.buffer.clear();
-            Ok(newline + 1)
-        } else {
-            self.buffer.extend(buf);
-            Ok(buf.len())
+
+struct HtmlMakeWriter;
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for HtmlMakeWriter {
+    type Writer = AnsiHtmlWriter;
+    fn make_writer(&'a self) -> Self::Writer {
+        AnsiHtmlWriter { buffer: Vec::new(), level: tracing::Level::INFO, target: "".into() }
+    }
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        AnsiHtmlWriter { buffer: Vec::new(), level: *meta.level(), target: meta.target().into() }
+    }
+}
+
+// Recent lines for late subscribers; new lines are added and sent under this lock, so
+// subscribe() sees every line exactly once
+static LOG_HISTORY: std::sync::Mutex<std::collections::VecDeque<std::sync::Arc<LogLine>>> =
+    std::sync::Mutex::new(std::collections::VecDeque::new());
+static LOG_HISTORY_LEN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn publish(line: LogLine) {
+    let Some(tx) = LOG_LISTENER.get() else { return };
+    let line = std::sync::Arc::new(line);
+    let mut history = LOG_HISTORY.lock().unwrap();
+    let max = LOG_HISTORY_LEN.load(std::sync::atomic::Ordering::Relaxed);
+    if max > 0 {
+        if history.len() >= max {
+            history.pop_front();
         }
+        history.push_back(std::sync::Arc::clone(&line));
     }
-    fn flush(&mut self) -> std::io::Result<()> {
+    tx.send(line).ok();
+}
+
+/// Whether anyone would see HTML lines; formatting them is skipped otherwise. Receivers
+/// include the one returned by [`LoggerConfig::setup`], until it's dropped.
+fn html_wanted() -> bool {
+    LOG_HISTORY_LEN.load(std::sync::atomic::Ordering::Relaxed) > 0
+        || LOG_LISTENER.get().is_some_and(|tx| tx.receiver_count() > 0)
+}
+
+/// The recent log lines kept by the logger, and a receiver for everything after them
+pub fn subscribe() -> Option<(Vec<std::sync::Arc<LogLine>>, LogReceiver)> {
+    let tx = LOG_LISTENER.get()?;
+    let history = LOG_HISTORY.lock().unwrap();
+    Some((history.iter().cloned().collect(), tx.subscribe()))
+}
+
+/// Handle to change the `RUST_LOG`-style filter of the logger set up by [`setup_logger`]
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: tracing_subscriber::reload::Handle<Targets, Registry>,
+    initial: Targets,
+    /// What [`LogFilter::reset`] goes back to: the startup filter, or the last one from a reload
+    base: std::sync::Arc<std::sync::Mutex<Targets>>,
+    /// Set through [`LogFilter::set`], which reloads then leave alone
+    overridden: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    // Bumped on every change, so a timed revert doesn't undo a newer change
+    generation: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LogFilterError {
+    #[error("Invalid log filter {:?}", .0)]
+    Invalid(String, #[source] tracing_subscriber::filter::ParseError),
+    #[error("Failed to update log filter")]
+    Reload(#[source] tracing_subscriber::reload::Error),
+}
+
+impl LogFilter {
+    /// The filter in effect, in `RUST_LOG` syntax
+    pub fn current(&self) -> String {
+        self.handle.with_current(|targets| targets.to_string()).unwrap_or_default()
+    }
+
+    /// Replace the filter until [`LogFilter::reset`]; reloads through [`LogFilter::reloader`]
+    /// don't undo it
+    pub fn set(&self, spec: &str) -> Result<(), LogFilterError> {
+        let targets = spec.parse::<Targets>().map_err(|e| LogFilterError::Invalid(spec.into(), e))?;
+        self.replace(targets, true)?;
+        info!("Log filter set to {:?}", spec);
+        Ok(())
+    }
+
+    /// Like [`LogFilter::set`], but resets after `duration`
+    /// unless the filter was changed again in the meantime. Needs a tokio runtime.
+    pub fn set_for(&self, spec: &str, duration: std::time::Duration) -> Result<(), LogFilterError> {
+        self.set(spec)?;
+        let generation = self.generation.load(std::sync::atomic::Ordering::SeqCst);
+        let filter = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            if filter.generation.load(std::sync::atomic::Ordering::SeqCst) == generation {
+                filter.reset().ok();
+            }
+        });
+        Ok(())
+    }
+
+    /// Go back to the filter from startup, or from the last reload
+    pub fn reset(&self) -> Result<(), LogFilterError> {
+        let base = self.base.lock().unwrap().clone();
+        self.replace(base.clone(), false)?;
+        info!("Log filter reset to {}", base);
+        Ok(())
+    }
+
+    fn replace(&self, targets: Targets, overridden: bool) -> Result<(), LogFilterError> {
+        self.generation.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.overridden.store(overridden, std::sync::atomic::Ordering::SeqCst);
+        self.handle.reload(targets).map_err(LogFilterError::Reload)
+    }
+
+    /// A reloader for [`crate::run`] that applies the filter returned by `source`, e.g. a field
+    /// of a reloaded config, or the startup filter when that returns `None`. While a filter set
+    /// through [`LogFilter::set`] is in effect, it's kept and this one only takes over on reset.
+    pub fn reloader(&self, mut source: impl FnMut() -> Option<String> + 'static) -> crate::reload::BoxedReload {
+        let filter = self.clone();
+        crate::reload::reloader(move |_| std::future::ready(filter.reload(source())))
+    }
+
+    fn reload(&self, spec: Option<String>) -> Result<(), LogFilterError> {
+        let targets = match spec {
+            Some(spec) => spec.parse::<Targets>().map_err(|e| LogFilterError::Invalid(spec, e))?,
+            None => self.initial.clone(),
+        };
+        *self.base.lock().unwrap() = targets.clone();
+        if self.overridden.load(std::sync::atomic::Ordering::SeqCst) {
+            info!("Keeping the log filter set at runtime, {} applies after a reset", targets);
+        } else if targets.to_string() != self.current() {
+            self.replace(targets.clone(), false)?;
+            info!("Log filter set to {}", targets);
+        }
         Ok(())
     }
 }
 
-pub static LOG_LISTENER: std::sync::OnceLock<tokio::sync::broadcast::Sender<std::sync::Arc<str>>> = std::sync::OnceLock::new();
+static LOG_FILTER: std::sync::OnceLock<LogFilter> = std::sync::OnceLock::new();
+
+/// The filter of the global logger, once [`setup_logger`] ran
+pub fn log_filter() -> Option<LogFilter> {
+    LOG_FILTER.get().cloned()
+}
+
+pub static LOG_LISTENER: std::sync::OnceLock<tokio::sync::broadcast::Sender<std::sync::Arc<LogLine>>> = std::sync::OnceLock::new();
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoggerError {
     #[error("Error parsing RUST_LOG env var into targets specifier")]
     InvalidLogEnv(tracing_subscriber::filter::ParseError),
+    #[error("Invalid LOG_FORMAT env var")]
+    InvalidLogFormat(#[source] UnknownLogFormat),
+    #[error("Failed to open log file {}", .0.display())]
+    File(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Failed to start the OTLP exporter")]
+    Otlp(#[source] std::io::Error),
     #[error("Log listener was already set? (setup_logger called twice)")]
     AlreadySet,
     #[error("Setting tracing listener failed")]
     SetFailed(tracing::subscriber::SetGlobalDefaultError),
 }
 
-pub fn setup_logger(crate_name: &'static str) -> Result<tokio::sync::broadcast::Receiver<std::sync::Arc<str>>, LoggerError> {
-    let env_targets = std::env::var("RUST_LOG")
-        .unwrap_or_else(|_| format!("{}=trace,runtime=debug,tower_http=debug,warn", crate_name));
-    let env_filter = env_targets.parse::<Targets>().map_err(LoggerError::InvalidLogEnv)?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Indented span tree, for terminals
+    #[default]
+    Pretty,
+    /// One line per event, prefixed with its spans and their fields
+    Compact,
+    /// Newline-delimited JSON with the fields of all enclosing spans, for log shippers
+    Json,
+}
 
-    let (tx, rx) = tokio::sync::broadcast::channel(10);
-    LOG_LISTENER.set(tx.clone()).map_err(|_| LoggerError::AlreadySet)?;
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown log format {:?} (expected pretty, compact or json)", .0)]
+pub struct UnknownLogFormat(String);
 
-    let subscriber = Registry::default()
-        // .with(tracing_subscriber::fmt::layer().with_filter(env_filter.clone()))
-        .with(tracing_tree::HierarchicalLayer::new(2)
-            .with_targets(true)
-            .with_bracketed_fields(true)
-            .with_filter(env_filter.clone())
-        )
+impl std::str::FromStr for LogFormat {
+    type Err = UnknownLogFormat;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "pretty" | "tree" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(UnknownLogFormat(s.into())),
+        }
+    }
+}
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Compact => "compact",
+            LogFormat::Json => "json",
+        })
+    }
+}
 
-        // .with(tracing_subscriber::fmt::layer()
-            // .with_ansi(false)
-            // .fmt_fields(tracing_subscriber::fmt::format::PrettyFields::new().with_ansi(false))
-            // .with_writer(move || AnsiHtmlWriter::from_channel(tx.clone()))
-            // .with_filter(env_filter.clone()))
-        ;
+/// Logger options beyond `RUST_LOG`. The format can come from an argument (`LogFormat`
+/// implements `FromStr` for [`crate::args::Flag::value`]), else from the `LOG_FORMAT` env var.
+pub struct LoggerConfig {
+    crate_name: &'static str,
+    format: Option<LogFormat>,
+    file: Option<file::FileSink>,
+    otlp: Option<otlp::OtlpExporter>,
+    history: usize,
+}
+impl LoggerConfig {
+    pub fn new(crate_name: &'static str) -> Self {
+        LoggerConfig { crate_name, format: None, file: None, otlp: None, history: 1000 }
+    }
+
+    /// How many recent lines [`subscribe`] hands to new subscribers (1000 by default). With 0,
+    /// events are only formatted as HTML while someone is subscribed.
+    pub fn history(self, lines: usize) -> Self {
+        LoggerConfig { history: lines, ..self }
+    }
+
+    /// Also write logs to a file, in addition to stdout/stderr
+    pub fn file(self, sink: file::FileSink) -> Self {
+        LoggerConfig { file: Some(sink), ..self }
+    }
 
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(LoggerError::SetFailed)?;
+    /// Export spans to an OpenTelemetry collector, instead of only when the `OTEL_EXPORTER_OTLP_*`
+    /// env vars are set (see [`otlp::OtlpExporter::from_env`]). Only spans the log filter lets through are sent.
+    pub fn otlp(self, exporter: otlp::OtlpExporter) -> Self {
+        LoggerConfig { otlp: Some(exporter), ..self }
+    }
+
+    /// Use this format instead of looking at `LOG_FORMAT`
+    pub fn format(self, format: LogFormat) -> Self {
+        LoggerConfig { format: Some(format), ..self }
+    }
+
+    pub fn setup(self) -> Result<LogReceiver, LoggerError> {
+        let env_targets = std::env::var("RUST_LOG")
+            .unwrap_or_else(|_| format!("{}=trace,runtime=debug,tower_http=debug,warn", self.crate_name));
+        let env_filter = env_targets.parse::<Targets>().map_err(LoggerError::InvalidLogEnv)?;
+
+        let format = match self.format {
+            Some(format) => format,
+            None => match std::env::var("LOG_FORMAT") {
+                Ok(format) => format.parse().map_err(LoggerError::InvalidLogFormat)?,
+                Err(_) => LogFormat::default(),
+            },
+        };
+
+        let (tx, rx) = tokio::sync::broadcast::channel(1024);
+        LOG_LISTENER.set(tx).map_err(|_| LoggerError::AlreadySet)?;
+        LOG_HISTORY_LEN.store(self.history, std::sync::atomic::Ordering::Relaxed);
+
+        let (filter, handle) = tracing_subscriber::reload::Layer::new(env_filter.clone());
+        LOG_FILTER.set(LogFilter {
+            handle,
+            initial: env_filter.clone(),
+            base: std::sync::Arc::new(std::sync::Mutex::new(env_filter.clone())),
+            overridden: Default::default(),
+            generation: Default::default(),
+        }).map_err(|_| LoggerError::AlreadySet)?;
+
+        let output = match format {
+            // The tree has always gone to stderr
+            LogFormat::Pretty => format_layer(format, std::io::stderr, true),
+            _ => format_layer(format, std::io::stdout, std::io::IsTerminal::is_terminal(&std::io::stdout())),
+        };
+        let file_output = match &self.file {
+            Some(sink) => {
+                let writer = sink.start().map_err(|e| LoggerError::File(sink.path.clone(), e))?;
+                Some(format_layer(sink.format, writer, false))
+            },
+            None => None,
+        };
+        let otlp_output = match self.otlp.or_else(otlp::OtlpExporter::from_env) {
+            Some(exporter) => Some(exporter.start(self.crate_name).map_err(LoggerError::Otlp)?),
+            None => None,
+        };
+
+        // Same look as the terminal, ansi_to_html takes care of the colors. Spans always go
+        // through, so events still show them once someone subscribes.
+        let html_output = tracing_subscriber::fmt::layer()
+            .with_ansi(true)
+            .with_writer(HtmlMakeWriter)
+            .with_filter(tracing_subscriber::filter::filter_fn(|meta| !meta.is_event() || html_wanted()));
+
+        // One filter in front of all outputs, so changing it through LogFilter affects them all
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(output)
+            .with(file_output)
+            .with(otlp_output)
+            .with(html_output);
+
+        tracing::subscriber::set_global_default(subscriber)
+            .map_err(LoggerError::SetFailed)?;
+
+        Ok(rx)
+    }
+}
+
+fn format_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'a> tracing_subscriber::fmt::MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => tracing_tree::HierarchicalLayer::new(2)
+            .with_targets(true)
+            .with_bracketed_fields(true)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer()
+            .compact()
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_ansi(false)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
 
-    Ok(rx)
+/// Logger with the format from `LOG_FORMAT` (the span tree by default), see [`LoggerConfig`]
+pub fn setup_logger(crate_name: &'static str) -> Result<LogReceiver, LoggerError> {
+    LoggerConfig::new(crate_name).setup()
 }
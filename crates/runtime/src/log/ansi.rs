@@ -0,0 +1,319 @@
+// ANSI escape sequences to HTML. SGR sequences (`ESC[...m`) are tracked as a current style,
+// which is written out as one flat <span> per run of equally styled text; every other
+// escape sequence is dropped.
+
+use crate::utils::parse_mut::{take_char, take_while};
+
+
+/// Safe in any html destination besides unquoted attributes (why do those exist...)
+fn write_html_escaped(w: &mut impl std::fmt::Write, text: &str) -> std::fmt::Result {
+    // Simplified version of ammonia's clean_text
+    for c in text.chars() {
+        let replacement = match c {
+            // this character, when confronted, will start a tag
+            '<' => "&lt;",
+            // in an unquoted attribute, will end the attribute value
+            '>' => "&gt;",
+            // in an attribute surrounded by double quotes, this character will end the attribute value
+            '\"' => "&quot;",
+            // in an attribute surrounded by single quotes, this character will end the attribute value
+            '\'' => "&apos;",
+            // starts an entity reference
+            '&' => "&amp;",
+            // a spec-compliant browser will perform this replacement anyway, but the middleware might not
+            '\0' => "&#65533;",
+            // ALL OTHER CHARACTERS ARE PASSED THROUGH VERBATIM
+            c => {
+                w.write_char(c)?;
+                continue;
+            }
+        };
+        w.write_str(replacement)?;
+    }
+    Ok(())
+}
+
+
+
+/// How [`ansi_to_html_with`] expresses styles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HtmlStyle {
+    /// `ansi-bold`, `ansi-fg-1`, `ansi-bg-236` and so on, for a stylesheet to define.
+    /// 24-bit colors don't fit in a class and always become inline styles.
+    #[default]
+    Classes,
+    /// Self-contained `style` attributes using the xterm palette
+    Inline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    blink: bool,
+    inverse: bool,
+    hidden: bool,
+    strike: bool,
+    fg: Color,
+    bg: Color,
+}
+
+impl Style {
+    fn apply_sgr(&mut self, params: &str) {
+        let mut groups = params.split(';');
+        while let Some(group) = groups.next() {
+            // ITU T.416 style "38:2::255:0:0", sub-parameters within one group
+            if group.contains(':') {
+                let mut sub = group.split(':');
+                let code = sub.next().and_then(|c| c.parse::<u16>().ok()).unwrap_or(0);
+                let mut sub = sub.collect::<Vec<_>>();
+                match code {
+                    38 | 48 | 58 => {
+                        // The color space id before r:g:b is optional
+                        if sub.first() == Some(&"2") && sub.len() >= 5 {
+                            sub.remove(1);
+                        }
+                        let color = parse_color(&mut sub.into_iter());
+                        self.set_extended(code, color);
+                    },
+                    // Curly, dotted, ... underlines are all just underlines here
+                    4 => self.underline = sub.first().is_none_or(|s| *s != "0"),
+                    _ => self.apply_code(code),
+                }
+                continue;
+            }
+
+            // Empty parameters count as 0, so "ESC[m" and "ESC[;1m" reset too
+            let code = if group.is_empty() { 0 } else {
+                match group.parse::<u16>() {
+                    Ok(code) => code,
+                    Err(_) => continue,
+                }
+            };
+            match code {
+                38 | 48 | 58 => {
+                    let color = parse_color(&mut groups);
+                    self.set_extended(code, color);
+                },
+                _ => self.apply_code(code),
+            }
+        }
+    }
+
+    fn set_extended(&mut self, code: u16, color: Option<Color>) {
+        match (code, color) {
+            (38, Some(color)) => self.fg = color,
+            (48, Some(color)) => self.bg = color,
+            // 58 is the underline color, which we don't show
+            _ => {},
+        }
+    }
+
+    fn apply_code(&mut self, code: u16) {
+        match code {
+            0 => *self = Style::default(),
+            1 => self.bold = true,
+            2 => self.dim = true,
+            3 => self.italic = true,
+            4 | 21 => self.underline = true,
+            5 | 6 => self.blink = true,
+            7 => self.inverse = true,
+            8 => self.hidden = true,
+            9 => self.strike = true,
+            22 => { self.bold = false; self.dim = false; },
+            23 => self.italic = false,
+            24 => self.underline = false,
+            25 => self.blink = false,
+            27 => self.inverse = false,
+            28 => self.hidden = false,
+            29 => self.strike = false,
+            30..=37 => self.fg = Color::Indexed((code - 30) as u8),
+            39 => self.fg = Color::Default,
+            40..=47 => self.bg = Color::Indexed((code - 40) as u8),
+            49 => self.bg = Color::Default,
+            90..=97 => self.fg = Color::Indexed((code - 90 + 8) as u8),
+            100..=107 => self.bg = Color::Indexed((code - 100 + 8) as u8),
+            // Fonts, frames, overlines and the like
+            _ => {},
+        }
+    }
+
+    fn write_span(&self, out: &mut String, mode: HtmlStyle) {
+        use std::fmt::Write;
+
+        let (fg, bg) = if self.inverse { (self.bg, self.fg) } else { (self.fg, self.bg) };
+        let mut classes = Vec::new();
+        let mut styles = Vec::new();
+
+        let flags = [
+            (self.bold, "bold", "font-weight:bold"),
+            (self.dim, "dim", "opacity:0.7"),
+            (self.italic, "italic", "font-style:italic"),
+            (self.blink, "blink", "text-decoration:blink"),
+            (self.inverse, "inverse", ""),
+            (self.hidden, "hidden", "visibility:hidden"),
+        ];
+        for (set, class, style) in flags {
+            if set {
+                match mode {
+                    HtmlStyle::Classes => classes.push(format!("ansi-{class}")),
+                    HtmlStyle::Inline if !style.is_empty() => styles.push(style.to_owned()),
+                    HtmlStyle::Inline => {},
+                }
+            }
+        }
+        // Both are text-decoration, so they have to go in one declaration
+        let decorations = [(self.underline, "underline"), (self.strike, "line-through")]
+            .into_iter().filter(|(set, _)| *set).map(|(_, d)| d).collect::<Vec<_>>();
+        match mode {
+            HtmlStyle::Classes => classes.extend(decorations.iter().map(|d| {
+                if *d == "underline" { "ansi-underline".to_owned() } else { "ansi-strike".to_owned() }
+            })),
+            HtmlStyle::Inline if !decorations.is_empty() => styles.push(format!("text-decoration:{}", decorations.join(" "))),
+            HtmlStyle::Inline => {},
+        }
+
+        for (color, prop, class, default_var) in [(fg, "color", "fg", "--ansi-bg"), (bg, "background-color", "bg", "--ansi-fg")] {
+            match (color, mode) {
+                (Color::Default, HtmlStyle::Inline) if self.inverse => {
+                    // Swapped default colors, which only the page knows
+                    let fallback = if prop == "color" { "#000" } else { "#fff" };
+                    styles.push(format!("{prop}:var({default_var},{fallback})"));
+                },
+                (Color::Default, _) => {},
+                (Color::Indexed(n), HtmlStyle::Classes) => classes.push(format!("ansi-{class}-{n}")),
+                (Color::Indexed(n), HtmlStyle::Inline) => {
+                    let (r, g, b) = palette(n);
+                    styles.push(format!("{prop}:#{r:02x}{g:02x}{b:02x}"));
+                },
+                (Color::Rgb(r, g, b), _) => styles.push(format!("{prop}:#{r:02x}{g:02x}{b:02x}")),
+            }
+        }
+
+        out.push_str("<span");
+        if !classes.is_empty() {
+            write!(out, " class='{}'", classes.join(" ")).ok();
+        }
+        if !styles.is_empty() {
+            write!(out, " style='{}'", styles.join(";")).ok();
+        }
+        out.push('>');
+    }
+}
+
+fn parse_color<'a>(args: &mut impl Iterator<Item = &'a str>) -> Option<Color> {
+    let mut next = || args.next().and_then(|a| a.parse::<u8>().ok());
+    match next()? {
+        5 => Some(Color::Indexed(next()?)),
+        2 => Some(Color::Rgb(next()?, next()?, next()?)),
+        _ => None,
+    }
+}
+
+/// xterm's default colors
+fn palette(n: u8) -> (u8, u8, u8) {
+    const BASIC: [(u8, u8, u8); 16] = [
+        (0x00, 0x00, 0x00), (0xcd, 0x00, 0x00), (0x00, 0xcd, 0x00), (0xcd, 0xcd, 0x00),
+        (0x00, 0x00, 0xee), (0xcd, 0x00, 0xcd), (0x00, 0xcd, 0xcd), (0xe5, 0xe5, 0xe5),
+        (0x7f, 0x7f, 0x7f), (0xff, 0x00, 0x00), (0x00, 0xff, 0x00), (0xff, 0xff, 0x00),
+        (0x5c, 0x5c, 0xff), (0xff, 0x00, 0xff), (0x00, 0xff, 0xff), (0xff, 0xff, 0xff),
+    ];
+    const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match n {
+        0..=15 => BASIC[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (CUBE[(n / 36) as usize], CUBE[(n / 6 % 6) as usize], CUBE[(n % 6) as usize])
+        },
+        232..=255 => {
+            let level = 8 + (n - 232) * 10;
+            (level, level, level)
+        },
+    }
+}
+
+/// Skips the escape sequence after an ESC, updating `style` if it was SGR
+fn skip_escape(input: &mut &str, style: &mut Style) {
+    match take_char(input) {
+        // CSI: parameter bytes, intermediate bytes, one final byte
+        Some('[') => {
+            let params = take_while(input, |c| matches!(c, '\x30'..='\x3F'));
+            let interm = take_while(input, |c| matches!(c, '\x20'..='\x2F'));
+            let mut rest = *input;
+            // A malformed one is dropped up to where it went wrong
+            if let Some(end @ '\x40'..='\x7E') = take_char(&mut rest) {
+                *input = rest;
+                // Private sequences like "ESC[>4;2m" end in m too but aren't SGR
+                let is_sgr = end == 'm' && interm.is_empty()
+                    && params.chars().all(|c| c.is_ascii_digit() || c == ';' || c == ':');
+                if is_sgr {
+                    style.apply_sgr(params);
+                }
+            }
+        },
+        // OSC (window titles, hyperlinks...), ends with BEL or ESC \
+        Some(']') => {
+            let end = input.find(['\x07', '\x1b']).unwrap_or(input.len());
+            *input = &input[end..];
+            if input.starts_with("\x1b\\") {
+                *input = &input[2..];
+            } else if input.starts_with('\x07') {
+                *input = &input[1..];
+            }
+        },
+        // Anything else is a two character sequence
+        _ => {},
+    }
+}
+
+/// [`ansi_to_html_with`] using classes
+pub fn ansi_to_html(msg: &str) -> String {
+    ansi_to_html_with(msg, HtmlStyle::Classes)
+}
+
+/// Escapes `msg` for HTML, turning SGR escape sequences into spans and dropping all others
+pub fn ansi_to_html_with(msg: &str, mode: HtmlStyle) -> String {
+    let mut out = String::with_capacity(msg.len());
+    let mut msg = msg;
+    let mut style = Style::default();
+    // Only opened once there's text to style, so runs of escapes collapse into one span
+    let mut open: Option<Style> = None;
+
+    loop {
+        let text_end = msg.find('\x1b').unwrap_or(msg.len());
+        let text = &msg[..text_end];
+        if !text.is_empty() {
+            let wanted = (style != Style::default()).then_some(style);
+            if open != wanted {
+                if open.is_some() {
+                    out.push_str("</span>");
+                }
+                if let Some(style) = &wanted {
+                    style.write_span(&mut out, mode);
+                }
+                open = wanted;
+            }
+            write_html_escaped(&mut out, text).ok();
+        }
+        if text_end == msg.len() {
+            break;
+        }
+        msg = &msg[text_end + 1..];
+        skip_escape(&mut msg, &mut style);
+    }
+
+    if open.is_some() {
+        out.push_str("</span>");
+    }
+    out
+}
@@ -0,0 +1,266 @@
+// Log file output. Formatted lines go through a bounded channel to a writer thread, so a slow
+// disk drops log lines (and says so in the file) instead of stalling whoever is logging.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use super::LogFormat;
+
+
+/// Log file settings for [`super::LoggerConfig::file`]
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    pub(super) path: PathBuf,
+    pub(super) format: LogFormat,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    keep: usize,
+    buffer_lines: usize,
+}
+impl FileSink {
+    /// Appends compact lines to `path`, without rotation. Old files are kept as `path.1`,
+    /// `path.2` and so on once rotation is enabled.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileSink {
+            path: path.into(),
+            format: LogFormat::Compact,
+            max_size: None,
+            rotate_every: None,
+            keep: 5,
+            buffer_lines: 8192,
+        }
+    }
+    pub fn format(self, format: LogFormat) -> Self {
+        FileSink { format, ..self }
+    }
+    /// Rotate before the file grows past `bytes`
+    pub fn max_size(self, bytes: u64) -> Self {
+        FileSink { max_size: Some(bytes), ..self }
+    }
+    /// Rotate every `interval`, counted from when the file was opened
+    pub fn rotate_every(self, interval: Duration) -> Self {
+        FileSink { rotate_every: Some(interval), ..self }
+    }
+    /// How many rotated files to keep (5 by default)
+    pub fn keep(self, count: usize) -> Self {
+        FileSink { keep: count, ..self }
+    }
+    /// Lines that can wait for the writer thread before new ones get dropped (8192 by default)
+    pub fn buffer_lines(self, lines: usize) -> Self {
+        FileSink { buffer_lines: lines, ..self }
+    }
+
+    pub(super) fn start(&self) -> std::io::Result<FileWriter> {
+        let file = OpenFile::open(&self.path)?;
+        let (tx, rx) = sync_channel(self.buffer_lines);
+        let dropped = Arc::<AtomicU64>::default();
+        let reopen = Arc::<AtomicBool>::default();
+
+        let worker = Worker {
+            settings: self.clone(),
+            file,
+            rx,
+            dropped: Arc::clone(&dropped),
+            reopen: Arc::clone(&reopen),
+        };
+        std::thread::Builder::new()
+            .name("log file writer".into())
+            .spawn(move || worker.run())?;
+
+        let writer = FileWriter { tx, dropped, reopen };
+        SINK.set(writer.clone()).ok();
+        Ok(writer)
+    }
+}
+
+enum Message {
+    Line(Vec<u8>),
+    /// Wakes up the writer to look at [`FileWriter::reopen`]
+    Reopen,
+    Flush(SyncSender<()>),
+}
+
+static SINK: OnceLock<FileWriter> = OnceLock::new();
+
+/// Close and reopen the log file, e.g. after logrotate moved it away. [`crate::run`] does this on reload.
+/// Never blocks; with a full queue the writer reopens once it gets to the next line.
+pub fn reopen() {
+    if let Some(sink) = SINK.get() {
+        sink.reopen.store(true, Ordering::Relaxed);
+        sink.tx.try_send(Message::Reopen).ok();
+    }
+}
+
+/// Wait (up to `timeout`) for queued lines to reach the log file. [`crate::run`] does this before returning.
+/// Skipped when the queue is full, since the writer is too far behind to catch up in time anyway.
+pub fn flush(timeout: Duration) {
+    if let Some(sink) = SINK.get() {
+        let (ack_tx, ack_rx) = sync_channel(1);
+        if sink.tx.try_send(Message::Flush(ack_tx)).is_ok() {
+            ack_rx.recv_timeout(timeout).ok();
+        }
+    }
+}
+
+
+#[derive(Clone)]
+pub(super) struct FileWriter {
+    tx: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+    /// Set by [`reopen`], so the request isn't lost when the queue is full
+    reopen: Arc<AtomicBool>,
+}
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for FileWriter {
+    type Writer = LineBuffer;
+    fn make_writer(&'a self) -> Self::Writer {
+        LineBuffer { buffer: Vec::new(), sink: self.clone() }
+    }
+}
+
+/// Collects one formatted event and queues it when dropped
+pub(super) struct LineBuffer {
+    buffer: Vec<u8>,
+    sink: FileWriter,
+}
+impl Write for LineBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl Drop for LineBuffer {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        match self.sink.tx.try_send(Message::Line(std::mem::take(&mut self.buffer))) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => { self.sink.dropped.fetch_add(1, Ordering::Relaxed); },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+
+struct OpenFile {
+    file: std::io::BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+impl OpenFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(OpenFile { file: std::io::BufWriter::new(file), size, opened: Instant::now() })
+    }
+}
+
+struct Worker {
+    settings: FileSink,
+    file: OpenFile,
+    rx: Receiver<Message>,
+    dropped: Arc<AtomicU64>,
+    reopen: Arc<AtomicBool>,
+}
+impl Worker {
+    fn run(mut self) {
+        while let Ok(message) = self.rx.recv() {
+            self.handle(message);
+            // Only hit the disk once there's nothing else queued
+            while let Ok(message) = self.rx.try_recv() {
+                self.handle(message);
+            }
+            self.flush();
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            self.flush();
+            self.replace_file();
+        }
+        match message {
+            Message::Line(line) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    let note = format!("... {dropped} log line(s) dropped, the log file writer fell behind\n");
+                    self.write(note.as_bytes());
+                }
+                self.write(&line);
+            },
+            Message::Reopen => {},
+            Message::Flush(ack) => {
+                self.flush();
+                ack.send(()).ok();
+            },
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.file.file.flush() {
+            report(&self.settings.path, e);
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if self.needs_rotation(data.len() as u64) {
+            self.rotate();
+        }
+        match self.file.file.write_all(data) {
+            Ok(()) => self.file.size += data.len() as u64,
+            Err(e) => report(&self.settings.path, e),
+        }
+    }
+
+    fn needs_rotation(&self, incoming: u64) -> bool {
+        let too_big = self.settings.max_size
+            .is_some_and(|max| self.file.size + incoming > max);
+        let too_old = self.settings.rotate_every
+            .is_some_and(|every| self.file.opened.elapsed() >= every);
+        // Never rotate away an empty file
+        self.file.size > 0 && (too_big || too_old)
+    }
+
+    fn rotate(&mut self) {
+        self.flush();
+        let path = &self.settings.path;
+        let rotated = |n: usize| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.settings.keep == 0 {
+            std::fs::remove_file(path).ok();
+        } else {
+            std::fs::remove_file(rotated(self.settings.keep)).ok();
+            for n in (1..self.settings.keep).rev() {
+                std::fs::rename(rotated(n), rotated(n + 1)).ok();
+            }
+            if let Err(e) = std::fs::rename(path, rotated(1)) {
+                report(path, e);
+            }
+        }
+        self.replace_file();
+    }
+
+    fn replace_file(&mut self) {
+        match OpenFile::open(&self.settings.path) {
+            Ok(file) => self.file = file,
+            // Keep writing to the old file rather than losing everything
+            Err(e) => report(&self.settings.path, e),
+        }
+    }
+}
+
+// Can't log about failing to log
+fn report(path: &Path, error: std::io::Error) {
+    eprintln!("Log file {}: {}", path.display(), error);
+}
@@ -0,0 +1,517 @@
+// Span export to an OpenTelemetry collector over OTLP/HTTP with JSON bodies. Finished spans go
+// through a bounded channel to an exporter thread that posts them in batches, so a slow or
+// missing collector drops spans instead of stalling the application.
+//
+// Spans pick up their trace from a `traceparent` field (W3C Trace Context, see
+// `runtime_axum::layers::make_trace_layer`) or else from their parent span, and `otel.kind`
+// (`server`, `client`, ...) sets the span kind.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::field::{Field, Visit};
+use tracing_subscriber::registry::LookupSpan;
+
+
+/// Where and how to send spans, for [`super::LoggerConfig::otlp`]
+#[derive(Debug, Clone)]
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: Option<String>,
+    headers: Vec<(String, String)>,
+    batch_size: usize,
+    flush_interval: Duration,
+    queue_spans: usize,
+}
+impl OtlpExporter {
+    /// Posts to `endpoint`, the full URL like `http://localhost:4318/v1/traces`
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        OtlpExporter {
+            endpoint: endpoint.into(),
+            service_name: None,
+            headers: Vec::new(),
+            batch_size: 512,
+            flush_interval: Duration::from_secs(5),
+            queue_spans: 2048,
+        }
+    }
+
+    /// From the standard `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or `OTEL_EXPORTER_OTLP_ENDPOINT`
+    /// (with `/v1/traces` appended) and `OTEL_SERVICE_NAME` env vars; `None` if neither endpoint is set
+    pub fn from_env() -> Option<Self> {
+        let env = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let endpoint = env("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .or_else(|| env("OTEL_EXPORTER_OTLP_ENDPOINT").map(|base| format!("{}/v1/traces", base.trim_end_matches('/'))))?;
+        let exporter = OtlpExporter::new(endpoint);
+        Some(match env("OTEL_SERVICE_NAME") {
+            Some(name) => exporter.service_name(name),
+            None => exporter,
+        })
+    }
+
+    /// The `service.name` resource attribute, the crate name by default
+    pub fn service_name(self, name: impl Into<String>) -> Self {
+        OtlpExporter { service_name: Some(name.into()), ..self }
+    }
+    /// Extra request header, e.g. for collector authentication
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+    /// Most spans per request (512 by default)
+    pub fn batch_size(self, spans: usize) -> Self {
+        OtlpExporter { batch_size: spans.max(1), ..self }
+    }
+    /// Longest a finished span waits to be sent (5 seconds by default)
+    pub fn flush_interval(self, interval: Duration) -> Self {
+        OtlpExporter { flush_interval: interval, ..self }
+    }
+    /// Finished spans that can wait for the exporter thread before new ones get dropped (2048 by default)
+    pub fn queue_spans(self, spans: usize) -> Self {
+        OtlpExporter { queue_spans: spans, ..self }
+    }
+
+    pub(super) fn start(&self, default_service_name: &str) -> std::io::Result<OtlpLayer> {
+        let (tx, rx) = sync_channel(self.queue_spans);
+        let dropped = Arc::<AtomicU64>::default();
+        let service_name = self.service_name.clone().unwrap_or_else(|| default_service_name.to_owned());
+
+        let worker = Worker { settings: self.clone(), service_name, rx, dropped: Arc::clone(&dropped) };
+        std::thread::Builder::new()
+            .name("otlp exporter".into())
+            .spawn(move || worker.run())?;
+
+        EXPORTER.set(tx.clone()).ok();
+        Ok(OtlpLayer { tx, dropped })
+    }
+}
+
+enum Message {
+    Span(FinishedSpan),
+    Flush(SyncSender<()>),
+}
+
+static EXPORTER: OnceLock<SyncSender<Message>> = OnceLock::new();
+
+/// Wait (up to `timeout`) for finished spans to be sent. [`crate::run`] does this before returning.
+/// Skipped when the queue is full, since the exporter is too far behind to catch up in time anyway.
+pub fn flush(timeout: Duration) {
+    if let Some(tx) = EXPORTER.get() {
+        let (ack_tx, ack_rx) = sync_channel(1);
+        if tx.try_send(Message::Flush(ack_tx)).is_ok() {
+            ack_rx.recv_timeout(timeout).ok();
+        }
+    }
+}
+
+
+/// A W3C Trace Context, as in `traceparent: 00-<trace id>-<span id>-<flags>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+impl TraceContext {
+    /// Parses a `traceparent` header value, `None` if it's malformed or has zero ids
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        // Later versions may append fields, but version 00 has exactly four
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        let hex = |s: &str| s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !hex(version) || !hex(trace_id) || !hex(span_id) || !hex(flags) {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|id| *id != 0)?;
+        let span_id = u64::from_str_radix(span_id, 16).ok().filter(|id| *id != 0)?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceContext { trace_id, span_id, sampled: flags & 1 == 1 })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, self.sampled as u8)
+    }
+
+    /// The context of the current span, for propagating to outgoing requests. `None` without
+    /// an exporter set up through [`super::LoggerConfig`].
+    pub fn current() -> Option<Self> {
+        tracing::Span::current().with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            extensions.get::<SpanData>().map(|data| data.context)
+        }).flatten()
+    }
+}
+
+fn new_id<T>(mut random: impl FnMut() -> T) -> T where T: Default + PartialEq {
+    // Zero means invalid in both trace and span ids
+    loop {
+        let id = random();
+        if id != T::default() {
+            return id;
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+type Attributes = Vec<(&'static str, Value)>;
+
+// Events on long-lived spans (like tasks) would otherwise pile up until the span closes
+const MAX_EVENTS: usize = 128;
+
+struct SpanEvent {
+    time: SystemTime,
+    name: String,
+    attributes: Attributes,
+}
+
+struct SpanData {
+    context: TraceContext,
+    parent_span_id: Option<u64>,
+    name: &'static str,
+    target: &'static str,
+    kind: u8,
+    start: SystemTime,
+    attributes: Attributes,
+    events: Vec<SpanEvent>,
+    dropped_events: u32,
+    error: Option<String>,
+}
+
+struct FinishedSpan {
+    data: SpanData,
+    end: SystemTime,
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    attributes: Attributes,
+    message: Option<String>,
+    traceparent: Option<String>,
+    kind: Option<u8>,
+}
+impl FieldVisitor {
+    fn add(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("message", Value::String(message)) => self.message = Some(message),
+            ("traceparent", Value::String(header)) => self.traceparent = Some(header),
+            ("otel.kind", Value::String(kind)) => self.kind = Some(span_kind(&kind)),
+            (name, value) => match self.attributes.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, old)) => *old = value,
+                None => self.attributes.push((name, value)),
+            },
+        }
+    }
+}
+impl Visit for FieldVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.add(field, Value::String(format!("{:?}", value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.add(field, Value::String(value.to_owned()));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add(field, Value::Int(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.add(field, Value::Int(value)),
+            Err(_) => self.add(field, Value::String(value.to_string())),
+        }
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.add(field, Value::Double(value));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add(field, Value::Bool(value));
+    }
+}
+
+// OTLP SpanKind values
+fn span_kind(kind: &str) -> u8 {
+    match &*kind.to_ascii_lowercase() {
+        "server" => 2,
+        "client" => 3,
+        "producer" => 4,
+        "consumer" => 5,
+        _ => 1,
+    }
+}
+
+
+/// Tracks trace context for every span and queues the sampled ones for export when they close
+pub(super) struct OtlpLayer {
+    tx: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+impl<S> tracing_subscriber::Layer<S> for OtlpLayer
+    where S: tracing::Subscriber + for<'a> LookupSpan<'a>
+{
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+
+        // A remote parent wins over whatever span the request happens to be handled in
+        let remote = fields.traceparent.as_deref().and_then(TraceContext::from_traceparent);
+        let local = span.parent().and_then(|parent| parent.extensions().get::<SpanData>().map(|p| p.context));
+        let parent = remote.or(local);
+        let context = TraceContext {
+            trace_id: parent.map(|p| p.trace_id).unwrap_or_else(|| new_id(rand::random::<u128>)),
+            span_id: new_id(rand::random::<u64>),
+            sampled: parent.is_none_or(|p| p.sampled),
+        };
+        let kind = fields.kind.unwrap_or(if remote.is_some() { 2 } else { 1 });
+        span.extensions_mut().insert(SpanData {
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            name: attrs.metadata().name(),
+            target: attrs.metadata().target(),
+            kind,
+            start: SystemTime::now(),
+            attributes: fields.attributes,
+            events: Vec::new(),
+            dropped_events: 0,
+            error: None,
+        });
+    }
+
+    fn on_record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        let Some(data) = extensions.get_mut::<SpanData>() else { return };
+        let mut fields = FieldVisitor { attributes: std::mem::take(&mut data.attributes), ..Default::default() };
+        values.record(&mut fields);
+        data.attributes = fields.attributes;
+        if let Some(kind) = fields.kind {
+            data.kind = kind;
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else { return };
+        let mut extensions = span.extensions_mut();
+        let Some(data) = extensions.get_mut::<SpanData>() else { return };
+        if !data.context.sampled {
+            return;
+        }
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+        let level = *event.metadata().level();
+        let name = fields.message.unwrap_or_else(|| event.metadata().name().to_owned());
+        if level == tracing::Level::ERROR {
+            data.error = Some(name.clone());
+        }
+        if data.events.len() >= MAX_EVENTS {
+            data.dropped_events += 1;
+            return;
+        }
+        fields.attributes.push(("level", Value::String(level.to_string())));
+        data.events.push(SpanEvent { time: SystemTime::now(), name, attributes: fields.attributes });
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else { return };
+        if !data.context.sampled {
+            return;
+        }
+        match self.tx.try_send(Message::Span(FinishedSpan { data, end: SystemTime::now() })) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => { self.dropped.fetch_add(1, Ordering::Relaxed); },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+
+struct Worker {
+    settings: OtlpExporter,
+    service_name: String,
+    rx: Receiver<Message>,
+    dropped: Arc<AtomicU64>,
+}
+impl Worker {
+    fn run(self) {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        let mut batch = Vec::new();
+        let mut deadline = Instant::now() + self.settings.flush_interval;
+        loop {
+            let message = self.rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+            let ack = match message {
+                Ok(Message::Span(span)) => {
+                    batch.push(span);
+                    if batch.len() < self.settings.batch_size {
+                        continue;
+                    }
+                    None
+                },
+                Ok(Message::Flush(ack)) => Some(ack),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            if !batch.is_empty() {
+                self.export(&agent, std::mem::take(&mut batch));
+            }
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                report(&self.settings.endpoint, format!("{dropped} span(s) dropped, the exporter fell behind"));
+            }
+            if let Some(ack) = ack {
+                ack.send(()).ok();
+            }
+            deadline = Instant::now() + self.settings.flush_interval;
+        }
+    }
+
+    fn export(&self, agent: &ureq::Agent, spans: Vec<FinishedSpan>) {
+        let body = export_request(&self.service_name, &spans).to_string();
+        let mut request = agent.post(&self.settings.endpoint)
+            .set("content-type", "application/json");
+        for (name, value) in &self.settings.headers {
+            request = request.set(name, value);
+        }
+        if let Err(e) = request.send_string(&body) {
+            report(&self.settings.endpoint, format!("{} span(s) lost: {}", spans.len(), e));
+        }
+    }
+}
+
+// Logging about failing to export would only make more spans to export
+fn report(endpoint: &str, error: String) {
+    eprintln!("OTLP export to {}: {}", endpoint, error);
+}
+
+
+// OTLP/JSON: ids are hex, 64-bit integers are strings, enums are numbers
+fn export_request(service_name: &str, spans: &[FinishedSpan]) -> serde_json::Value {
+    use serde_json::json;
+    let nanos = |time: SystemTime| time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+    let spans = spans.iter().map(|FinishedSpan { data, end }| {
+        let mut attributes = attributes_json(&data.attributes);
+        attributes.push(json!({ "key": "code.namespace", "value": { "stringValue": data.target } }));
+        let mut span = json!({
+            "traceId": format!("{:032x}", data.context.trace_id),
+            "spanId": format!("{:016x}", data.context.span_id),
+            "name": data.name,
+            "kind": data.kind,
+            "startTimeUnixNano": nanos(data.start),
+            "endTimeUnixNano": nanos(*end),
+            "attributes": attributes,
+            "events": data.events.iter().map(|event| json!({
+                "timeUnixNano": nanos(event.time),
+                "name": event.name,
+                "attributes": attributes_json(&event.attributes),
+            })).collect::<Vec<_>>(),
+            "droppedEventsCount": data.dropped_events,
+            "status": match &data.error {
+                // STATUS_CODE_ERROR
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({}),
+            },
+        });
+        if let Some(parent) = data.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        span
+    }).collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "runtime", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attributes_json(attributes: &Attributes) -> Vec<serde_json::Value> {
+    use serde_json::json;
+    attributes.iter().map(|(key, value)| {
+        let value = match value {
+            Value::String(s) => json!({ "stringValue": s }),
+            Value::Int(i) => json!({ "intValue": i.to_string() }),
+            Value::Double(d) => json!({ "doubleValue": d }),
+            Value::Bool(b) => json!({ "boolValue": b }),
+        };
+        json!({ "key": key, "value": value })
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Accepts one request and hands back its body
+    fn mock_collector() -> (String, std::thread::JoinHandle<serde_json::Value>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+            serde_json::from_slice(&body).unwrap()
+        });
+        (endpoint, collector)
+    }
+
+    #[test]
+    fn exports_span_with_remote_parent() {
+        let (endpoint, collector) = mock_collector();
+        let layer = OtlpExporter::new(endpoint).flush_interval(Duration::from_secs(60)).start("test").unwrap();
+        let subscriber = tracing_subscriber::Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+            tracing::info_span!("handle request", traceparent).in_scope(|| {});
+        });
+        flush(Duration::from_secs(5));
+
+        let body = collector.join().unwrap();
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "handle request");
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["kind"], 2);
+    }
+}
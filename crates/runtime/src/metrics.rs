@@ -0,0 +1,252 @@
+// A small Prometheus-style metrics registry: counters, gauges and histograms, optionally in
+// labelled families, rendered in the text exposition format.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+
+/// Something that can be written out in the exposition format under a given name and labels
+pub trait Metric: Clone + Send + Sync + 'static {
+    const TYPE: &'static str;
+    fn render(&self, name: &str, labels: &str, out: &mut String);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+impl Metric for Counter {
+    const TYPE: &'static str = "counter";
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        writeln!(out, "{name}{labels} {}", self.get()).ok();
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+impl Metric for Gauge {
+    const TYPE: &'static str = "gauge";
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        writeln!(out, "{name}{labels} {}", self.get()).ok();
+    }
+}
+
+/// Default buckets for request latencies in seconds, same as the official clients
+pub const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug)]
+struct HistogramInner {
+    bounds: Vec<f64>,
+    // Not cumulative, that happens when rendering
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: Mutex<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<HistogramInner>);
+impl Histogram {
+    /// `bounds` are the upper bounds of the buckets, a `+Inf` bucket is always added
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_by(f64::total_cmp);
+        Histogram(Arc::new(HistogramInner {
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            count: AtomicU64::new(0),
+            sum: Mutex::new(0.0),
+        }))
+    }
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.0.bounds.iter().position(|b| value <= *b) {
+            self.0.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.0.count.fetch_add(1, Ordering::Relaxed);
+        *self.0.sum.lock().unwrap() += value;
+    }
+}
+impl Metric for Histogram {
+    const TYPE: &'static str = "histogram";
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        // The le label goes after the others
+        let with_le = |le: &str| match labels.strip_suffix('}') {
+            Some(labels) => format!("{labels},le=\"{le}\"}}"),
+            None => format!("{{le=\"{le}\"}}"),
+        };
+        let mut cumulative = 0;
+        for (bound, bucket) in self.0.bounds.iter().zip(&self.0.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(out, "{name}_bucket{} {cumulative}", with_le(&bound.to_string())).ok();
+        }
+        let count = self.0.count.load(Ordering::Relaxed);
+        writeln!(out, "{name}_bucket{} {count}", with_le("+Inf")).ok();
+        writeln!(out, "{name}_sum{labels} {}", *self.0.sum.lock().unwrap()).ok();
+        writeln!(out, "{name}_count{labels} {count}").ok();
+    }
+}
+
+
+/// Metrics of one kind under one name, told apart by label values
+pub struct Family<M> {
+    label_names: &'static [&'static str],
+    metrics: Mutex<BTreeMap<Vec<String>, M>>,
+    make: Box<dyn Fn() -> M + Send + Sync>,
+}
+impl<M: Metric> Family<M> {
+    /// The metric for these label values (in the order of the label names), created on first use
+    pub fn with(&self, values: &[&str]) -> M {
+        debug_assert_eq!(values.len(), self.label_names.len(), "wrong number of label values");
+        let key = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        self.metrics.lock().unwrap()
+            .entry(key)
+            .or_insert_with(|| (self.make)())
+            .clone()
+    }
+}
+
+trait Collect: Send + Sync {
+    fn collect(&self, name: &str, out: &mut String);
+}
+impl<M: Metric> Collect for Family<M> {
+    fn collect(&self, name: &str, out: &mut String) {
+        for (values, metric) in self.metrics.lock().unwrap().iter() {
+            let labels = if values.is_empty() {
+                String::new()
+            } else {
+                let pairs = self.label_names.iter().zip(values)
+                    .map(|(name, value)| format!("{name}=\"{}\"", escape_label(value)))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", pairs.join(","))
+            };
+            metric.render(name, &labels, out);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct Registered {
+    name: String,
+    help: String,
+    kind: &'static str,
+    family: Arc<dyn Collect>,
+    any: Arc<dyn Any + Send + Sync>,
+}
+
+/// A set of metrics to be rendered together, usually [`global`]
+#[derive(Default)]
+pub struct Registry {
+    metrics: Mutex<Vec<Registered>>,
+}
+impl Registry {
+    pub const fn new() -> Self {
+        Registry { metrics: Mutex::new(Vec::new()) }
+    }
+
+    /// Register a labelled family of metrics, or get the one already registered under `name`.
+    /// Panics if `name` is taken by a different kind of metric.
+    pub fn family<M: Metric>(
+        &self,
+        name: &str,
+        help: &str,
+        label_names: &'static [&'static str],
+        make: impl Fn() -> M + Send + Sync + 'static,
+    ) -> Arc<Family<M>> {
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Some(existing) = metrics.iter().find(|m| m.name == name) {
+            return Arc::clone(&existing.any).downcast::<Family<M>>()
+                .unwrap_or_else(|_| panic!("metric {name} is already registered as a {}", existing.kind));
+        }
+        let family = Arc::new(Family { label_names, metrics: Mutex::default(), make: Box::new(make) });
+        metrics.push(Registered {
+            name: name.into(),
+            help: help.into(),
+            kind: M::TYPE,
+            family: Arc::clone(&family) as Arc<dyn Collect>,
+            any: Arc::clone(&family) as Arc<dyn Any + Send + Sync>,
+        });
+        family
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        self.family(name, help, &[], Counter::default).with(&[])
+    }
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        self.family(name, help, &[], Gauge::default).with(&[])
+    }
+    pub fn histogram(&self, name: &str, help: &str, bounds: &[f64]) -> Histogram {
+        let bounds = bounds.to_vec();
+        self.family(name, help, &[], move || Histogram::new(&bounds)).with(&[])
+    }
+    pub fn counter_vec(&self, name: &str, help: &str, labels: &'static [&'static str]) -> Arc<Family<Counter>> {
+        self.family(name, help, labels, Counter::default)
+    }
+    pub fn gauge_vec(&self, name: &str, help: &str, labels: &'static [&'static str]) -> Arc<Family<Gauge>> {
+        self.family(name, help, labels, Gauge::default)
+    }
+    pub fn histogram_vec(&self, name: &str, help: &str, labels: &'static [&'static str], bounds: &[f64]) -> Arc<Family<Histogram>> {
+        let bounds = bounds.to_vec();
+        self.family(name, help, labels, move || Histogram::new(&bounds))
+    }
+
+    /// Everything in the Prometheus text exposition format (version 0.0.4)
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in self.metrics.lock().unwrap().iter() {
+            let help = metric.help.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(out, "# HELP {} {}", metric.name, help).ok();
+            writeln!(out, "# TYPE {} {}", metric.name, metric.kind).ok();
+            metric.family.collect(&metric.name, &mut out);
+        }
+        out
+    }
+}
+
+static GLOBAL: Registry = Registry::new();
+
+/// The registry [`crate::run`] records task metrics in
+pub fn global() -> &'static Registry {
+    &GLOBAL
+}
+
+
+pub(crate) struct TaskMetrics {
+    pub up: Arc<Family<Gauge>>,
+    pub restarts: Arc<Family<Counter>>,
+}
+
+pub(crate) fn task_metrics() -> &'static TaskMetrics {
+    static METRICS: OnceLock<TaskMetrics> = OnceLock::new();
+    METRICS.get_or_init(|| TaskMetrics {
+        up: global().gauge_vec("runtime_task_up", "Whether the task is running", &["task"]),
+        restarts: global().counter_vec("runtime_task_restarts_total", "Times the task was restarted after exiting", &["task"]),
+    })
+}
@@ -0,0 +1,168 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::task::{IntoTaskResult, TaskError, TaskResult};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadTrigger {
+    /// The initial value seen by subscribers before any reload happened
+    Startup,
+    Sighup,
+    /// Requested through [`crate::RunHandle::signal_reload`], with an optional reason
+    Request(Option<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ReloadEvent {
+    /// Increases by one for every successful reload
+    pub generation: u64,
+    pub trigger: ReloadTrigger,
+}
+
+
+pub type ReloadFuture = Pin<Box<dyn Future<Output = TaskResult>>>;
+type ReloadFn = Box<dyn FnMut(ReloadEvent) -> ReloadFuture>;
+
+/// Which step of a [`BoxedReload::then`] chain failed
+#[derive(Debug, thiserror::Error)]
+enum ChainError {
+    #[error("First reloader failed, the next one was skipped")]
+    First(#[source] TaskError),
+    #[error("Next reloader failed")]
+    Next(#[source] TaskError),
+    #[error("Rollback of the first reloader failed")]
+    FirstRollback(#[source] TaskError),
+    #[error("Rollback of the next reloader failed")]
+    NextRollback(#[source] TaskError),
+}
+
+/// Reload callback for [`crate::run`], with an optional rollback that runs when it fails
+pub struct BoxedReload {
+    reload: ReloadFn,
+    rollback: Option<ReloadFn>,
+}
+impl BoxedReload {
+    pub fn rollback<Func, Fut>(self, mut f: Func) -> Self
+    where
+        Func: FnMut(ReloadEvent) -> Fut + 'static,
+        Fut: Future + 'static,
+        Fut::Output: IntoTaskResult,
+    {
+        BoxedReload {
+            rollback: Some(Box::new(move |event| {
+                let future = f(event);
+                Box::pin(async move { future.await.into_task_result() })
+            })),
+            ..self
+        }
+    }
+
+    /// Run `next` after this reload succeeds, e.g. to reload config and TLS certificates together.
+    /// When `next` fails, both are rolled back, `next` first; when this one fails, only this one is.
+    pub fn then(self, next: BoxedReload) -> Self {
+        let mut first = self.reload;
+        let next_reload = Rc::new(RefCell::new(next.reload));
+        // Whether the last reload got as far as running `next`
+        let ran_next = Rc::new(Cell::new(false));
+
+        let reload: ReloadFn = Box::new({
+            let ran_next = Rc::clone(&ran_next);
+            move |event| {
+                ran_next.set(false);
+                let first = first(event.clone());
+                let next = Rc::clone(&next_reload);
+                let ran_next = Rc::clone(&ran_next);
+                Box::pin(async move {
+                    first.await.map_err(ChainError::First)?;
+                    ran_next.set(true);
+                    let next = (next.borrow_mut())(event);
+                    next.await.map_err(ChainError::Next)?;
+                    Ok(())
+                })
+            }
+        });
+
+        let rollback: Option<ReloadFn> = match (self.rollback, next.rollback) {
+            (None, None) => None,
+            (first_rollback, next_rollback) => {
+                let first_rollback = Rc::new(RefCell::new(first_rollback));
+                let next_rollback = Rc::new(RefCell::new(next_rollback));
+                Some(Box::new(move |event| {
+                    let ran_next = ran_next.get();
+                    let first_rollback = Rc::clone(&first_rollback);
+                    let next_rollback = Rc::clone(&next_rollback);
+                    Box::pin(async move {
+                        let next = match next_rollback.borrow_mut().as_mut() {
+                            Some(rollback) if ran_next => Some(rollback(event.clone())),
+                            _ => None,
+                        };
+                        let next_result = match next {
+                            Some(next) => next.await.map_err(ChainError::NextRollback),
+                            None => Ok(()),
+                        };
+                        let first = first_rollback.borrow_mut().as_mut().map(|rollback| rollback(event));
+                        let first_result = match first {
+                            Some(first) => first.await.map_err(ChainError::FirstRollback),
+                            None => Ok(()),
+                        };
+                        if let (Err(_), Err(e)) = (&next_result, &first_result) {
+                            error!("{}", crate::utils::format_error_disp(e));
+                        }
+                        next_result.and(first_result)?;
+                        Ok(())
+                    })
+                }))
+            },
+        };
+
+        BoxedReload { reload, rollback }
+    }
+
+    /// Runs the reload and, if it failed, the rollback; only a successful reload returns `true`
+    pub(crate) async fn run(&mut self, event: &ReloadEvent) -> bool {
+        match (self.reload)(event.clone()).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Reload failed: {}", crate::utils::format_error_disp(&*e));
+                if let Some(rollback) = &mut self.rollback {
+                    match rollback(event.clone()).await {
+                        Ok(()) => info!("Rolled back failed reload"),
+                        Err(e) => error!("Rollback failed: {}", crate::utils::format_error_disp(&*e)),
+                    }
+                }
+                false
+            },
+        }
+    }
+}
+
+pub fn reloader<Func, Fut>(mut f: Func) -> BoxedReload
+where
+    Func: FnMut(ReloadEvent) -> Fut + 'static,
+    Fut: Future + 'static,
+    Fut::Output: IntoTaskResult,
+{
+    BoxedReload {
+        reload: Box::new(move |event| {
+            let future = f(event);
+            Box::pin(async move { future.await.into_task_result() })
+        }),
+        rollback: None,
+    }
+}
+
+/// Adapts a plain synchronous callback into a reloader that can't fail
+pub fn reload_fn(mut f: impl FnMut() + 'static) -> BoxedReload {
+    reloader(move |_| {
+        f();
+        std::future::ready(())
+    })
+}
+
+/// A reloader that does nothing, for apps which only use [`crate::RunHandle::subscribe_reload`]
+pub fn no_reload() -> BoxedReload {
+    reloader(|_| std::future::ready(()))
+}
@@ -0,0 +1,172 @@
+// sd_notify(3) and sd_listen_fds(3) without linking libsystemd.
+// Everything here is a no-op when the process wasn't started by systemd.
+
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+
+/// Send a raw state string like "READY=1" to `$NOTIFY_SOCKET`; returns false if it isn't set
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        },
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(std::io::ErrorKind::Unsupported.into()),
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        },
+    }
+    Ok(true)
+}
+
+pub(crate) fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("Failed to notify systemd ({:?}): {}", state, e);
+    }
+}
+
+pub fn notify_ready() {
+    notify_or_warn("READY=1");
+}
+/// Type=notify-reload needs the reload start time along with RELOADING=1
+pub fn notify_reloading() {
+    notify_or_warn(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+}
+pub fn notify_stopping() {
+    notify_or_warn("STOPPING=1");
+}
+pub fn notify_watchdog() {
+    notify_or_warn("WATCHDOG=1");
+}
+/// Tell systemd another process has taken over as the main process of the service
+pub fn notify_mainpid(pid: u32) {
+    notify_or_warn(&format!("MAINPID={pid}"));
+}
+
+fn monotonic_usec() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: ts is a valid timespec to write to, and CLOCK_MONOTONIC always exists
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+fn env_is_for_us(var: &str) -> bool {
+    match std::env::var(var) {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        // WATCHDOG_PID is optional, LISTEN_PID is not
+        Err(_) => var == "WATCHDOG_PID",
+    }
+}
+
+/// How often to send WATCHDOG=1, half of the `WatchdogSec=` systemd gave us
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 || !env_is_for_us("WATCHDOG_PID") {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+
+const LISTEN_FDS_START: i32 = 3;
+static LISTEN_FDS: std::sync::Mutex<Option<ListenFds>> = std::sync::Mutex::new(None);
+
+struct ListenFds {
+    /// How many were passed, including the ones already taken
+    passed: usize,
+    remaining: Vec<(String, OwnedFd)>,
+}
+
+fn receive_listen_fds() -> Vec<(String, OwnedFd)> {
+    // Sockets can also come from the process we're replacing, see crate::upgrade
+    if !env_is_for_us("LISTEN_PID") && !crate::upgrade::is_upgrade_child() {
+        return Vec::new();
+    }
+    let count: i32 = std::env::var("LISTEN_FDS").ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    let mut names = std::env::var("LISTEN_FDNAMES").unwrap_or_default()
+        .split(':').map(String::from).collect::<Vec<_>>().into_iter();
+
+    (LISTEN_FDS_START .. LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Don't leak these into anything we spawn
+            // SAFETY: fcntl on an fd number is fine even if it turned out not to be open
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let name = names.next().filter(|n| !n.is_empty()).unwrap_or_else(|| "unknown".into());
+            // SAFETY: these were passed to us, and LISTEN_FDS is only read once so nothing else takes them
+            (name, unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+fn with_listen_fds<T>(f: impl FnOnce(&mut ListenFds) -> T) -> T {
+    let mut fds = LISTEN_FDS.lock().unwrap();
+    f(fds.get_or_insert_with(|| {
+        let remaining = receive_listen_fds();
+        ListenFds { passed: remaining.len(), remaining }
+    }))
+}
+
+/// How many sockets systemd socket activation passed, including the ones already taken
+pub fn listen_fds_passed() -> usize {
+    with_listen_fds(|fds| fds.passed)
+}
+
+/// Take ownership of all remaining sockets passed by systemd socket activation, along
+/// with their `FileDescriptorName=` (or "unknown")
+pub fn take_listen_fds() -> Vec<(String, OwnedFd)> {
+    with_listen_fds(|fds| std::mem::take(&mut fds.remaining))
+}
+
+/// Take the passed socket with the given name, if there is one
+pub fn take_listen_fd(name: &str) -> Option<OwnedFd> {
+    with_listen_fds(|fds| {
+        let index = fds.remaining.iter().position(|(n, _)| n == name)?;
+        Some(fds.remaining.remove(index).1)
+    })
+}
+
+/// Take the passed socket whatever its name, but only if it's the only one that was passed;
+/// with several, picking one would be a guess
+pub fn take_any_listen_fd() -> Option<(String, OwnedFd)> {
+    with_listen_fds(|fds| (fds.passed == 1 && !fds.remaining.is_empty()).then(|| fds.remaining.remove(0)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_socket() {
+        let dir = std::env::temp_dir().join(format!("runtime-notify-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        std::fs::remove_file(&path).ok();
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+
+        notify_ready();
+        notify_stopping();
+        notify_watchdog();
+
+        let mut buf = [0; 256];
+        for expected in ["READY=1", "STOPPING=1", "WATCHDOG=1"] {
+            let len = socket.recv(&mut buf).unwrap();
+            assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(), expected);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
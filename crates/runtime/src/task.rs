@@ -0,0 +1,235 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+
+
+pub type TaskError = Box<dyn std::error::Error + Send + Sync + 'static>;
+pub type TaskResult = Result<(), TaskError>;
+
+pub(crate) type TaskFuture = Pin<Box<dyn Future<Output = TaskResult> + Send + 'static>>;
+type TaskFactory = Box<dyn FnMut(CancellationToken) -> Option<TaskFuture>>;
+
+/// Lets tasks return either `()` or a `Result`, so failures can be told apart from clean exits
+pub trait IntoTaskResult {
+    fn into_task_result(self) -> TaskResult;
+}
+impl IntoTaskResult for () {
+    fn into_task_result(self) -> TaskResult {
+        Ok(())
+    }
+}
+impl<E> IntoTaskResult for Result<(), E> where E: Into<TaskError> {
+    fn into_task_result(self) -> TaskResult {
+        self.map_err(Into::into)
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartMode {
+    /// Any exit of the task shuts down the whole process
+    #[default]
+    Never,
+    /// Restart when the task panics or returns an error
+    OnFailure,
+    /// Restart whenever the task exits, even cleanly
+    Always,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// Consecutive restarts allowed before giving up and shutting down; `None` is unlimited
+    pub max_restarts: Option<u32>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// A task that stayed up at least this long gets its restart count and backoff reset
+    pub reset_after: Duration,
+}
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            mode: RestartMode::Never,
+            max_restarts: Some(5),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(5 * 60),
+        }
+    }
+}
+impl RestartPolicy {
+    pub fn never() -> Self {
+        Self::default()
+    }
+    pub fn on_failure() -> Self {
+        RestartPolicy { mode: RestartMode::OnFailure, ..Self::default() }
+    }
+    pub fn always() -> Self {
+        RestartPolicy { mode: RestartMode::Always, ..Self::default() }
+    }
+    pub fn max_restarts(self, max_restarts: Option<u32>) -> Self {
+        RestartPolicy { max_restarts, ..self }
+    }
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        RestartPolicy { initial_backoff: initial, max_backoff: max, ..self }
+    }
+    pub fn reset_after(self, reset_after: Duration) -> Self {
+        RestartPolicy { reset_after, ..self }
+    }
+
+    pub(crate) fn wants_restart(&self, failed: bool) -> bool {
+        match self.mode {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => failed,
+            RestartMode::Always => true,
+        }
+    }
+    /// Delay before the given (zero-based) consecutive restart
+    pub(crate) fn backoff_for(&self, restarts: u32) -> Duration {
+        let factor = 1u32.checked_shl(restarts).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+
+tokio::task_local! {
+    static READY: ReadySignal;
+}
+
+/// Which task is reporting readiness to [`crate::run`]
+#[derive(Clone)]
+pub(crate) struct ReadySignal {
+    pub(crate) index: usize,
+    pub(crate) tx: flume::Sender<usize>,
+}
+impl ReadySignal {
+    pub(crate) fn scope(self, future: TaskFuture) -> TaskFuture {
+        Box::pin(READY.scope(self, future))
+    }
+}
+
+/// Report that the calling task is serving, for tasks started with [`BoxedTask::signals_ready`].
+/// Does nothing outside of [`crate::run`]'s tasks (in tasks they spawn, too).
+pub fn notify_ready() {
+    READY.try_with(|ready| ready.tx.send(ready.index).ok()).ok();
+}
+
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A task for [`crate::run`]; a factory so the supervisor can start it again after it exits
+pub struct BoxedTask {
+    factory: TaskFactory,
+    pub(crate) restart: RestartPolicy,
+    pub(crate) depends_on: Vec<&'static str>,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) signals_ready: bool,
+}
+impl BoxedTask {
+    fn new(factory: TaskFactory) -> Self {
+        BoxedTask {
+            factory,
+            restart: RestartPolicy::never(),
+            depends_on: Vec::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            signals_ready: false,
+        }
+    }
+
+    pub fn restart(self, restart: RestartPolicy) -> Self {
+        BoxedTask { restart, ..self }
+    }
+    /// Names of tasks that must outlive this one; on shutdown this task is cancelled
+    /// and waited for before any of them are
+    pub fn depends_on(mut self, tasks: &[&'static str]) -> Self {
+        self.depends_on.extend_from_slice(tasks);
+        self
+    }
+    /// How long shutdown waits for this task before moving on to the next phase
+    pub fn shutdown_timeout(self, shutdown_timeout: Duration) -> Self {
+        BoxedTask { shutdown_timeout, ..self }
+    }
+
+    /// The task calls [`notify_ready`] once it's serving, like `runtime_axum`'s servers do. Until
+    /// then it counts as starting, and the process isn't reported ready to systemd or to the
+    /// process it's replacing in an upgrade. Other tasks count as ready once they're spawned.
+    pub fn signals_ready(self) -> Self {
+        BoxedTask { signals_ready: true, ..self }
+    }
+
+    /// Returns `None` if the task can't be started again (see [`handler_once`])
+    pub(crate) fn start(&mut self, cancel: CancellationToken) -> Option<TaskFuture> {
+        (self.factory)(cancel)
+    }
+}
+
+pub fn handler<Func, Fut>(mut f: Func) -> BoxedTask
+where
+    Func: FnMut(CancellationToken) -> Fut + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoTaskResult,
+{
+    BoxedTask::new(Box::new(move |c| {
+        let future = f(c);
+        Some(Box::pin(async move { future.await.into_task_result() }))
+    }))
+}
+
+/// Like [`handler`] for closures that can only run once; these are never restarted
+pub fn handler_once<Func, Fut>(f: Func) -> BoxedTask
+where
+    Func: FnOnce(CancellationToken) -> Fut + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoTaskResult,
+{
+    let mut f = Some(f);
+    BoxedTask::new(Box::new(move |c| {
+        let future = f.take()?(c);
+        Some(Box::pin(async move { future.await.into_task_result() }))
+    }))
+}
+
+
+/// Assigns each task a shutdown phase from the declared dependencies: tasks nothing depends on
+/// are in phase 0 and stopped first, every dependency is in a later phase than its dependents.
+pub(crate) fn shutdown_phases(tasks: &[(&'static str, &[&'static str])]) -> Result<Vec<usize>, crate::RunError> {
+    // dependents[i] lists the tasks which depend on task i
+    let mut dependents = vec![Vec::new(); tasks.len()];
+    for (index, (ident, deps)) in tasks.iter().enumerate() {
+        for dep in deps.iter() {
+            let dep_index = tasks.iter().position(|(name, _)| name == dep)
+                .ok_or(crate::RunError::UnknownDependency { task: ident, dependency: dep })?;
+            dependents[dep_index].push(index);
+        }
+    }
+
+    fn phase(
+        index: usize,
+        tasks: &[(&'static str, &[&'static str])],
+        dependents: &[Vec<usize>],
+        phases: &mut [Option<usize>],
+        visiting: &mut Vec<usize>,
+    ) -> Result<usize, crate::RunError> {
+        if let Some(phase) = phases[index] {
+            return Ok(phase);
+        }
+        if visiting.contains(&index) {
+            return Err(crate::RunError::DependencyCycle(tasks[index].0));
+        }
+        visiting.push(index);
+        let mut max = 0;
+        for &dependent in &dependents[index] {
+            max = max.max(phase(dependent, tasks, dependents, phases, visiting)? + 1);
+        }
+        visiting.pop();
+        phases[index] = Some(max);
+        Ok(max)
+    }
+
+    let mut phases = vec![None; tasks.len()];
+    (0..tasks.len())
+        .map(|i| phase(i, tasks, &dependents, &mut phases, &mut Vec::new()))
+        .collect()
+}
@@ -0,0 +1,239 @@
+// Zero-downtime binary upgrades: the running process starts a fresh copy of its (possibly
+// replaced) executable, hands it the registered listening sockets the same way systemd socket
+// activation does, waits for it to report readiness and then shuts itself down gracefully.
+
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+
+const PARENT_ENV: &str = "RUNTIME_UPGRADE_PARENT";
+const NOTIFY_FD_ENV: &str = "RUNTIME_UPGRADE_NOTIFY_FD";
+
+#[derive(Debug, thiserror::Error)]
+pub enum UpgradeError {
+    #[error("No listening sockets are registered for handoff")]
+    NoListeners,
+    #[error("Failed to start the new process")]
+    Spawn(#[source] std::io::Error),
+    #[error("Error while waiting for the new process")]
+    Wait(#[source] std::io::Error),
+    #[error("New process exited before becoming ready ({})", .0)]
+    ChildExited(std::process::ExitStatus),
+    #[error("New process didn't become ready within {}s", .0.as_secs_f32())]
+    Timeout(Duration),
+}
+
+
+struct Registered {
+    id: u64,
+    name: String,
+    fd: OwnedFd,
+}
+static LISTENERS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Keeps a listening socket available for handoff until dropped
+pub struct ListenerRegistration(u64);
+impl Drop for ListenerRegistration {
+    fn drop(&mut self) {
+        LISTENERS.lock().unwrap().retain(|l| l.id != self.0);
+    }
+}
+
+/// Offer a listening socket to the process started on upgrade, under `name` in `LISTEN_FDNAMES`
+/// (which must not contain ':')
+pub fn register_listener(name: impl Into<String>, fd: BorrowedFd<'_>) -> std::io::Result<ListenerRegistration> {
+    let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let fd = fd.try_clone_to_owned()?;
+    LISTENERS.lock().unwrap().push(Registered { id, name: name.into(), fd });
+    Ok(ListenerRegistration(id))
+}
+
+
+/// Whether this process was started by [`spawn_upgrade`] of a still running parent, which is
+/// still serving on its sockets
+pub fn is_upgrade_child() -> bool {
+    std::env::var(PARENT_ENV).ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::os::unix::process::parent_id())
+}
+
+static NOTIFIED_PARENT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Tell the process we're replacing that we're up; does nothing if there isn't one
+pub(crate) fn notify_parent_ready() {
+    if NOTIFIED_PARENT.swap(true, std::sync::atomic::Ordering::SeqCst) || !is_upgrade_child() {
+        return;
+    }
+    let Some(fd) = std::env::var(NOTIFY_FD_ENV).ok().and_then(|fd| fd.parse::<RawFd>().ok()) else {
+        return;
+    };
+    // SAFETY: the parent put its end of the socketpair at this fd, and the flag above makes sure it's only taken once
+    let socket = unsafe { std::os::unix::net::UnixDatagram::from_raw_fd(fd) };
+    if let Err(e) = socket.send(b"READY=1") {
+        warn!("Failed to notify parent process of readiness: {}", e);
+    }
+}
+
+fn current_exe() -> std::io::Result<std::path::PathBuf> {
+    let exe = std::env::current_exe()?;
+    // When the binary was replaced on disk, Linux reports the old one as "<path> (deleted)"
+    match exe.to_str().and_then(|s| s.strip_suffix(" (deleted)")) {
+        Some(path) => Ok(path.into()),
+        None => Ok(exe),
+    }
+}
+
+/// Start a new copy of this executable with the same arguments, passing it the registered
+/// listeners, and wait until it's ready. Returns the pid of the new process.
+pub async fn spawn_upgrade(timeout: Duration) -> Result<u32, UpgradeError> {
+    let (parent_end, child_end) = std::os::unix::net::UnixDatagram::pair().map_err(UpgradeError::Spawn)?;
+
+    let (names, fds) = {
+        let listeners = LISTENERS.lock().unwrap();
+        if listeners.is_empty() {
+            return Err(UpgradeError::NoListeners);
+        }
+        // Move everything above the range it'll end up in, so the dup2 calls can't clobber each other
+        let min_fd = 3 + listeners.len() as RawFd + 1;
+        let fds = listeners.iter().map(|l| l.fd.as_fd())
+            .chain(std::iter::once(child_end.as_fd()))
+            .map(|fd| dup_above(fd, min_fd))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(UpgradeError::Spawn)?;
+        let names = listeners.iter().map(|l| &l.name[..]).collect::<Vec<_>>().join(":");
+        (names, fds)
+    };
+    let listen_fds = fds.len() - 1;
+    let raw_fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+
+    let exe = current_exe().map_err(UpgradeError::Spawn)?;
+    info!("Starting {} to take over {} listener(s)", exe.display(), listen_fds);
+
+    let mut command = tokio::process::Command::new(exe);
+    command.args(std::env::args_os().skip(1));
+    child_env(command.as_std_mut(), &names, listen_fds);
+    // SAFETY: dup2 is async-signal-safe, and raw_fds stay open until after spawn
+    unsafe {
+        command.pre_exec(move || {
+            for (i, fd) in raw_fds.iter().enumerate() {
+                // dup2 clears CLOEXEC on the new fd, so these survive exec
+                if libc::dup2(*fd, 3 + i as RawFd) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().map_err(UpgradeError::Spawn)?;
+    drop(fds);
+    drop(child_end);
+
+    parent_end.set_nonblocking(true).map_err(UpgradeError::Wait)?;
+    let parent_end = tokio::net::UnixDatagram::from_std(parent_end).map_err(UpgradeError::Wait)?;
+    let pid = child.id().unwrap_or_default();
+    let mut abandoned = AbandonGuard(Some(pid));
+
+    let wait_ready = async {
+        let mut buf = [0; 256];
+        loop {
+            let len = parent_end.recv(&mut buf).await?;
+            if buf[..len].split(|c| *c == b'\n').any(|line| line == b"READY=1") {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    };
+
+    let result = tokio::select! {
+        res = wait_ready => res.map_err(UpgradeError::Wait),
+        status = child.wait() => match status {
+            Ok(status) => Err(UpgradeError::ChildExited(status)),
+            Err(e) => Err(UpgradeError::Wait(e)),
+        },
+        _ = tokio::time::sleep(timeout) => Err(UpgradeError::Timeout(timeout)),
+    };
+    if result.is_err() {
+        child.start_kill().ok();
+    }
+    abandoned.0 = None;
+    result.map(|()| pid)
+}
+
+/// Kills the new process if the upgrade is dropped before it's ready, e.g. when we're told
+/// to shut down meanwhile
+struct AbandonGuard(Option<u32>);
+impl Drop for AbandonGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.filter(|&pid| pid != 0) {
+            // SAFETY: plain syscall; the child can't have been reaped yet, since that only
+            // happens by awaiting child.wait() to completion
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+}
+
+/// How the new process finds its sockets and the pipe back to us
+fn child_env(command: &mut std::process::Command, names: &str, listen_fds: usize) {
+    command
+        .env("LISTEN_FDS", listen_fds.to_string())
+        .env("LISTEN_FDNAMES", names)
+        .env_remove("LISTEN_PID")
+        // It becomes the main process and takes over the watchdog, and an unset WATCHDOG_PID
+        // means whoever reads it (its pid isn't known before spawning)
+        .env_remove("WATCHDOG_PID")
+        .env(PARENT_ENV, std::process::id().to_string())
+        .env(NOTIFY_FD_ENV, (3 + listen_fds).to_string());
+}
+
+fn dup_above(fd: BorrowedFd<'_>, min_fd: RawFd) -> std::io::Result<OwnedFd> {
+    // SAFETY: fd is valid for the duration of the call, and F_DUPFD_CLOEXEC returns a new fd we own
+    let new = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min_fd) };
+    if new < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: checked above that this is a freshly created fd
+    Ok(unsafe { OwnedFd::from_raw_fd(new) })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHILD_ENV: &str = "RUNTIME_TEST_WATCHDOG_CHILD";
+
+    #[test]
+    fn child_keeps_watchdog() {
+        // Running as the new process started below
+        if std::env::var_os(CHILD_ENV).is_some() {
+            if crate::systemd::watchdog_interval().is_some() {
+                crate::systemd::notify_watchdog();
+            }
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("runtime-watchdog-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        // As systemd would have set it up for us
+        let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+        command
+            .args(["upgrade::tests::child_keeps_watchdog", "--exact", "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .env("NOTIFY_SOCKET", &path)
+            .env("WATCHDOG_USEC", "1000000")
+            .env("WATCHDOG_PID", std::process::id().to_string())
+            .stdout(std::process::Stdio::null());
+        child_env(&mut command, "", 0);
+        assert!(command.status().unwrap().success());
+
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -14,7 +14,7 @@ pub mod parse_mut {
                 return found;
             }
         }
-        std::mem::replace(input, "")
+        std::mem::take(input)
     }
 }
 
@@ -66,7 +66,7 @@ impl<'a, T> std::fmt::Write for Indented<'a, T> where T: std::fmt::Write {
     }
 }
 
-pub fn format_error<E, W>(f: &mut W, error: &E) -> Result<(), std::fmt::Error> where W: std::fmt::Write, E: std::error::Error {
+pub fn format_error<E, W>(f: &mut W, error: &E) -> Result<(), std::fmt::Error> where W: std::fmt::Write, E: std::error::Error + ?Sized {
     use std::fmt::Write;
     write!(f, "{}", error)?;
 
@@ -86,9 +86,9 @@ pub fn format_error<E, W>(f: &mut W, error: &E) -> Result<(), std::fmt::Error> w
     Ok(())
 }
 
-pub fn format_error_disp<'a, E>(e: &'a E) -> impl std::fmt::Display + 'a where E: std::error::Error {
-    struct Disp<'a, E>(&'a E);
-    impl<E> std::fmt::Display for Disp<'_, E> where E: std::error::Error {
+pub fn format_error_disp<'a, E>(e: &'a E) -> impl std::fmt::Display + 'a where E: std::error::Error + ?Sized {
+    struct Disp<'a, E: ?Sized>(&'a E);
+    impl<E> std::fmt::Display for Disp<'_, E> where E: std::error::Error + ?Sized {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             format_error(f, self.0)
         }